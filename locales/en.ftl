@@ -0,0 +1,144 @@
+## Interface strings of the site. The file name is the locale, pages in it
+## are served under its prefix, like /en. language-name is the name of
+## the language in the language itself.
+
+language-name = English
+
+site-title = Mira Strannaya's website
+site-name-first = Website of
+site-name-second = Mira Strannaya
+to-index = To the front page
+who-is-it = Who is that?
+subscribe = Subscribe
+write = Write
+sign-in = Sign in
+sign-out = Sign out
+copyright = © 2020 - 2022 Mira Strannaya.
+license = This website is available under the GNU AGPLv3 license.
+source-code = The source code is available at
+source-code-link = this link
+
+error-401 = Error 401
+country-blocked = Access to this page is restricted for your country.
+error-404 = Error 404
+not-found = There is no such page(
+error-500 = Error 500
+server-error = The server broke(
+
+authorization = Sign in
+enter-token = Enter the token:
+
+edited = edited
+mentions = Mentions
+comments = Comments
+comment = Comment
+no-comments-yet = No comments yet.
+reply = Reply
+name = Name
+name-label = Name:
+comment-label = Comment (Markdown can be used):
+send = Send
+leave-empty = Leave this field empty:
+
+comment-not-sent = The comment is not sent
+thanks = Thank you!
+comment-pending = The comment will appear after moderation.
+back-to-article = Back to the article
+name-length = The name must be from 1 to { $max } characters long.
+comment-length = The comment must be from 1 to { $max } characters long.
+comment-parent-not-found = The comment you are replying to is not found.
+
+pending = Pending
+approved = approved
+rejected = rejected
+spam = spam
+comment-to = on
+reply-to = reply to #{ $id }
+approve = Approve
+reject = Reject
+mark-spam = Spam
+no-comments = No comments.
+
+write-to-me = Write to me
+message-sent = Thank you, the message is sent!
+reply-email-label = Email to reply to:
+message-label = Message:
+email-invalid = The email address is wrong.
+message-length = The message must be from 1 to { $max } characters long.
+
+messages = Messages
+undelivered-messages = Undelivered messages
+no-messages = No messages.
+delete = Delete
+message-not-sent = The message is not sent
+form-unverified = It could not be checked that a person sent the form. Turn on JavaScript and try again.
+form-expired = The form is outdated. Reload the page and try again.
+form-too-fast = The form was sent too fast. Try again.
+form-too-many-links = The message has too many links.
+form-spam = The message looks like spam.
+
+subscription = Subscription
+subscription-heading = Subscription to new articles
+email-label = Email:
+subscription-sent = Check your mail: to start the subscription, follow the link in the letter.
+subscription-confirmed = The subscription is confirmed. New articles will come by mail.
+unsubscribe-question = Stop sending letters about new articles to { $email }?
+unsubscribe = Unsubscribe
+unsubscribed = You have unsubscribed, no more letters will come.
+bad-link = The link is wrong or outdated.
+
+media-library = Media library
+file-label = File (at most { $size }):
+description-label = Description:
+upload = Upload
+
+## Letters are plain text. Subscribers get them in the locale they subscribed
+## in, the letters to the author of the site are in the default one.
+
+contact-mail-subject = Message from { $name } on the website
+subscription-mail-subject = Confirm the subscription
+subscription-mail-body =
+    To get the new articles of { $host } by mail, follow the link:
+
+    { $link }
+
+    If you did not subscribe, just ignore this letter.
+digest-mail-subject = New on { $host }
+digest-unsubscribe = Unsubscribe: { $link }
+
+## Gemini and Gopher clients do not tell their language, so the mirrors are
+## in the default locale.
+
+internal-error = Internal server error
+bad-request = Bad request
+page-not-found = Page not found
+site-on-web = This site on the web
+article-on-web = This article on the web
+image = Image
+
+## Dates are formatted with chrono, month and day names are taken from
+## date-locale.
+
+date-locale = en_US
+date-format = %B %-d, %Y, %H:%M %Z
+relative-now = just now
+relative-minutes = { $count ->
+    [one] a minute ago
+   *[other] { $count } minutes ago
+}
+relative-hours = { $count ->
+    [one] an hour ago
+   *[other] { $count } hours ago
+}
+relative-days = { $count ->
+    [one] a day ago
+   *[other] { $count } days ago
+}
+relative-months = { $count ->
+    [one] a month ago
+   *[other] { $count } months ago
+}
+relative-years = { $count ->
+    [one] a year ago
+   *[other] { $count } years ago
+}
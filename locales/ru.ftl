@@ -0,0 +1,149 @@
+## Interface strings of the site. The file name is the locale, pages in it
+## are served under its prefix, like /ru. language-name is the name of
+## the language in the language itself.
+
+language-name = Русский
+
+site-title = Сайт Миры Странной
+site-name-first = Сайт
+site-name-second = Миры Странной
+to-index = На главную
+who-is-it = Кто это?
+subscribe = Подписаться
+write = Написать
+sign-in = Войти
+sign-out = Выйти
+copyright = © 2020 - 2022 Мира Странная.
+license = Этот сайт доступен по лицензии GNU AGPLv3.
+source-code = Исходный код доступен по
+source-code-link = этой ссылке
+
+error-401 = Ошибка 401
+country-blocked = Доступ к странице для вашей страны ограничен.
+error-404 = Ошибка 404
+not-found = Такой страницы нема(
+error-500 = Ошибка 500
+server-error = Сервер наебнулся(
+
+authorization = Авторизация
+enter-token = Введите токен:
+
+edited = ред.
+mentions = Упоминания
+comments = Комментарии
+comment = Комментарий
+no-comments-yet = Комментариев пока нет.
+reply = Ответить
+name = Имя
+name-label = Имя:
+comment-label = Комментарий (можно использовать Markdown):
+send = Отправить
+leave-empty = Оставьте это поле пустым:
+
+comment-not-sent = Комментарий не отправлен
+thanks = Спасибо!
+comment-pending = Комментарий появится после проверки.
+back-to-article = Вернуться к статье
+name-length = Имя должно быть от 1 до { $max } символов.
+comment-length = Комментарий должен быть от 1 до { $max } символов.
+comment-parent-not-found = Комментарий, на который вы отвечаете, не найден.
+
+pending = На проверке
+approved = одобренные
+rejected = отклонённые
+spam = спам
+comment-to = к
+reply-to = ответ на #{ $id }
+approve = Одобрить
+reject = Отклонить
+mark-spam = Спам
+no-comments = Комментариев нет.
+
+write-to-me = Написать мне
+message-sent = Спасибо, сообщение отправлено!
+reply-email-label = Электронная почта для ответа:
+message-label = Сообщение:
+email-invalid = Адрес электронной почты указан неверно.
+message-length = Сообщение должно быть от 1 до { $max } символов.
+
+messages = Сообщения
+undelivered-messages = Недоставленные сообщения
+no-messages = Сообщений нет.
+delete = Удалить
+message-not-sent = Сообщение не отправлено
+form-unverified = Не удалось проверить, что форму отправил человек. Включите JavaScript и попробуйте ещё раз.
+form-expired = Форма устарела. Обновите страницу и попробуйте ещё раз.
+form-too-fast = Форма отправлена слишком быстро. Попробуйте ещё раз.
+form-too-many-links = В сообщении слишком много ссылок.
+form-spam = Сообщение похоже на спам.
+
+subscription = Подписка
+subscription-heading = Подписка на новые статьи
+email-label = Электронная почта:
+subscription-sent = Проверьте почту: чтобы подписка заработала, перейдите по ссылке из письма.
+subscription-confirmed = Подписка подтверждена. Новые статьи будут приходить на почту.
+unsubscribe-question = Отписать { $email } от писем о новых статьях?
+unsubscribe = Отписаться
+unsubscribed = Вы отписались, письма больше не будут приходить.
+bad-link = Ссылка неверна или устарела.
+
+media-library = Медиатека
+file-label = Файл (не больше { $size }):
+description-label = Описание:
+upload = Загрузить
+
+## Letters are plain text. Subscribers get them in the locale they subscribed
+## in, the letters to the author of the site are in the default one.
+
+contact-mail-subject = Сообщение с сайта от { $name }
+subscription-mail-subject = Подтвердите подписку
+subscription-mail-body =
+    Чтобы получать новые статьи с сайта { $host } по почте, перейдите по ссылке:
+
+    { $link }
+
+    Если вы не подписывались, просто проигнорируйте это письмо.
+digest-mail-subject = Новое на сайте { $host }
+digest-unsubscribe = Отписаться: { $link }
+
+## Gemini and Gopher clients do not tell their language, so the mirrors are
+## in the default locale.
+
+internal-error = Внутренняя ошибка сервера
+bad-request = Неверный запрос
+page-not-found = Страница не найдена
+site-on-web = Этот сайт в вебе
+article-on-web = Эта статья в вебе
+image = Изображение
+
+## Dates are formatted with chrono, month and day names are taken from
+## date-locale.
+
+date-locale = ru_RU
+date-format = %d.%m.%Y %H:%M %Z
+relative-now = только что
+relative-minutes = { $count ->
+    [one] { $count } минуту назад
+    [few] { $count } минуты назад
+   *[many] { $count } минут назад
+}
+relative-hours = { $count ->
+    [one] { $count } час назад
+    [few] { $count } часа назад
+   *[many] { $count } часов назад
+}
+relative-days = { $count ->
+    [one] { $count } день назад
+    [few] { $count } дня назад
+   *[many] { $count } дней назад
+}
+relative-months = { $count ->
+    [one] { $count } месяц назад
+    [few] { $count } месяца назад
+   *[many] { $count } месяцев назад
+}
+relative-years = { $count ->
+    [one] { $count } год назад
+    [few] { $count } года назад
+   *[many] { $count } лет назад
+}
@@ -0,0 +1,754 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::HashSet;
+use std::os::unix::fs::OpenOptionsExt;
+use std::io::Write;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::error::BlockingError;
+use actix_web::http::{ HeaderValue, header };
+use chrono::{ DateTime, SecondsFormat, Utc };
+use once_cell::sync::Lazy;
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{ PKey, Private, Public };
+use openssl::rsa::Rsa;
+use openssl::sha::sha256;
+use openssl::sign::{ Signer, Verifier };
+use regex::Regex;
+use rusqlite::{ params, Connection };
+use serde::Deserialize;
+use serde_json::{ json, Value };
+use url::Url;
+
+use crate::assets::encode_path;
+use crate::config::{ ActivityPub, Config };
+use crate::errors::*;
+use crate::http::{ HttpClient, Request };
+use crate::pages;
+use crate::post::Post;
+use crate::publish::{ Change, Event, Listener };
+use crate::state::State;
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+const MAX_CLOCK_SKEW: i64 = 12 * 60 * 60;
+
+static SIGNATURE_PARAMETER: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(\w+)="([^"]*)""#).unwrap());
+
+pub struct Federation {
+    config: Arc<Config>,
+    settings: ActivityPub,
+    key: PKey<Private>,
+    client: Arc<dyn HttpClient>,
+}
+
+#[derive(Deserialize)]
+pub struct WebfingerQuery {
+    resource: String,
+}
+
+impl Federation {
+    // The key identifies the blog to other servers, so it is generated once
+    // and kept next to the database.
+    pub fn new(config: Arc<Config>,
+               settings: ActivityPub,
+               client: Arc<dyn HttpClient>) -> Result<Federation, Box<dyn Error>> {
+        let path = Path::new(&settings.key_file);
+
+        let key = if path.exists() {
+            PKey::private_key_from_pem(&fs::read(path)?)?
+        } else {
+            let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?
+                .write_all(&key.private_key_to_pem_pkcs8()?)?;
+
+            key
+        };
+
+        Ok(Federation { config, settings, key, client })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("https://{}{}", self.config.host, path)
+    }
+
+    fn actor_id(&self) -> String {
+        self.url("/actor")
+    }
+
+    fn key_id(&self) -> String {
+        format!("{}#main-key", self.actor_id())
+    }
+
+    fn actor(&self) -> Result<Value, Box<dyn Error>> {
+        let settings = &self.settings;
+
+        Ok(json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1"
+            ],
+            "id": self.actor_id(),
+            "type": "Person",
+            "preferredUsername": settings.username,
+            "name": settings.name,
+            "summary": settings.summary,
+            "url": self.url("/"),
+            "inbox": self.url("/actor/inbox"),
+            "outbox": self.url("/actor/outbox"),
+            "followers": self.url("/actor/followers"),
+            "manuallyApprovesFollowers": false,
+            "publicKey": {
+                "id": self.key_id(),
+                "owner": self.actor_id(),
+                "publicKeyPem": String::from_utf8(self.key.public_key_to_pem()?)?,
+            },
+        }))
+    }
+
+    fn article(&self, post: &Post) -> Value {
+        let url = self.url(&format!("/articles/{}", encode_path(&post.link)));
+
+        let mut object = json!({
+            "id": url,
+            "type": "Article",
+            "url": url,
+            "name": post.name,
+            "content": post.text,
+            "attributedTo": self.actor_id(),
+            "to": [PUBLIC],
+            "cc": [self.url("/actor/followers")],
+        });
+
+        if let Some(date) = &post.date {
+            object["published"] = json!(date.0.to_rfc3339_opts(SecondsFormat::Secs, true));
+        }
+
+        if let Some(lastmod) = &post.lastmod {
+            object["updated"] = json!(lastmod.0.to_rfc3339_opts(SecondsFormat::Secs, true));
+        }
+
+        object
+    }
+
+    // Ids stay the same between outbox requests, an Update gets a new one
+    // for every edit.
+    fn activity(&self, kind: &str, object: Value) -> Value {
+        let object_id = object["id"].as_str().unwrap_or_default();
+
+        let id = match object["updated"].as_str() {
+            Some(updated) if kind != "Create" => format!("{}#{}-{}", object_id, kind.to_lowercase(), updated),
+            _ => format!("{}#{}", object_id, kind.to_lowercase()),
+        };
+
+        json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": id,
+            "type": kind,
+            "actor": self.actor_id(),
+            "to": object["to"].clone(),
+            "cc": object["cc"].clone(),
+            "object": object,
+        })
+    }
+
+    // Signs with the draft-cavage HTTP Signatures everybody in the fediverse
+    // speaks, covering the body through the Digest header.
+    fn sign(&self, request: Request) -> Result<Request, Box<dyn Error>> {
+        let url = Url::parse(&request.url)?;
+        let host = url.host_str().ok_or("URL without host")?;
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+
+        let target = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+
+        let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+        let mut names = vec!["(request-target)", "host", "date"];
+        let mut lines = vec![
+            format!("(request-target): {} {}", request.method.to_lowercase(), target),
+            format!("host: {}", host),
+            format!("date: {}", date),
+        ];
+
+        let mut request = request.header("Date", &date);
+
+        if let Some(body) = &request.body {
+            let digest = format!("SHA-256={}", base64::encode_block(&sha256(body)));
+
+            names.push("digest");
+            lines.push(format!("digest: {}", digest));
+            request = request.header("Digest", &digest);
+        }
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+
+        signer.update(lines.join("\n").as_bytes())?;
+
+        let signature = format!("keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
+                                self.key_id(),
+                                names.join(" "),
+                                base64::encode_block(&signer.sign_to_vec()?));
+
+        Ok(request.header("Signature", &signature))
+    }
+
+    fn fetch(&self, url: &str) -> Result<Value, Box<dyn Error>> {
+        check_remote(url)?;
+
+        let request = Request::get(url).header("Accept", ACTIVITY_JSON);
+        let response = self.client.send(self.sign(request)?)?;
+
+        if !response.success() {
+            return Err(format!("{} answered {}", url, response.status).into());
+        }
+
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+
+    fn deliver(&self, inbox: &str, activity: &Value) -> Result<(), Box<dyn Error>> {
+        check_remote(inbox)?;
+
+        let request = Request::post(inbox, ACTIVITY_JSON, serde_json::to_vec(activity)?);
+        let response = self.client.send(self.sign(request)?)?;
+
+        if !response.success() {
+            return Err(format!("{} answered {}", inbox, response.status).into());
+        }
+
+        Ok(())
+    }
+
+    // Returns the actor that signed the request, after checking the
+    // signature against its published key and that the actor claims the key.
+    fn verify(&self,
+              method: &str,
+              target: &str,
+              headers: &[(String, String)],
+              body: &[u8]) -> Result<String, Box<dyn Error>> {
+        let header = |name: &str| headers.iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let parameters: Vec<(String, String)> = SIGNATURE_PARAMETER
+            .captures_iter(&header("Signature"))
+            .map(|caps| (caps[1].to_owned(), caps[2].to_owned()))
+            .collect();
+
+        let parameter = |name: &str| parameters.iter()
+            .find(|(parameter, _)| parameter == name)
+            .map(|(_, value)| value.as_str())
+            .ok_or(format!("Signature without {}", name));
+
+        let key_id = parameter("keyId")?;
+        let signed: Vec<&str> = parameter("headers")?.split_whitespace().collect();
+
+        for required in &["(request-target)", "host", "date", "digest"] {
+            if !signed.contains(required) {
+                return Err(format!("Signature does not cover {}", required).into());
+            }
+        }
+
+        let expected_digest = format!("SHA-256={}", base64::encode_block(&sha256(body)));
+
+        if !header("Digest").split(',').any(|digest| digest.trim() == expected_digest) {
+            return Err("Digest does not match the body".into());
+        }
+
+        let date = DateTime::parse_from_rfc2822(&header("Date"))?;
+
+        if (Utc::now().timestamp() - date.timestamp()).abs() > MAX_CLOCK_SKEW {
+            return Err("Date is too far from now".into());
+        }
+
+        let lines: Vec<String> = signed.iter()
+            .map(|name| match *name {
+                "(request-target)" => format!("(request-target): {} {}", method.to_lowercase(), target),
+                name => format!("{}: {}", name, header(name)),
+            })
+            .collect();
+
+        let document = self.fetch(key_id.split('#').next().unwrap_or(key_id))?;
+
+        // The key may be embedded in the actor or be a document of its own.
+        let key = if document["publicKey"].is_object() {
+            &document["publicKey"]
+        } else {
+            &document
+        };
+
+        if key["id"].as_str() != Some(key_id) {
+            return Err("Fetched key does not match keyId".into());
+        }
+
+        let owner = key["owner"].as_str().ok_or("Key without owner")?;
+        let pem = key["publicKeyPem"].as_str().ok_or("Key without PEM")?;
+        let public: PKey<Public> = PKey::public_key_from_pem(pem.as_bytes())?;
+
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public)?;
+
+        verifier.update(lines.join("\n").as_bytes())?;
+
+        if !verifier.verify(&base64::decode_block(parameter("signature")?)?)? {
+            return Err("Signature verification failed".into());
+        }
+
+        // Anyone can publish a key naming somebody else as its owner, so the
+        // owner has to live on the same server and list the key as its own.
+        if Url::parse(owner)?.origin() != Url::parse(key_id)?.origin() {
+            return Err(format!("Key {} is foreign to {}", key_id, owner).into());
+        }
+
+        let fetched;
+        let actor = if document["id"].as_str() == Some(owner) {
+            &document
+        } else {
+            fetched = self.fetch(owner)?;
+            &fetched
+        };
+
+        if actor["publicKey"]["id"].as_str() != Some(key_id) {
+            return Err(format!("{} does not claim key {}", owner, key_id).into());
+        }
+
+        Ok(owner.to_owned())
+    }
+
+    fn handle(&self,
+              conn: &Connection,
+              signer: &str,
+              activity: &Value) -> Result<(), Box<dyn Error>> {
+        let actor = activity["actor"].as_str().ok_or("Activity without actor")?;
+
+        if actor != signer {
+            return Err(format!("{} signed an activity of {}", signer, actor).into());
+        }
+
+        match activity["type"].as_str() {
+            Some("Follow") if activity["object"].as_str() == Some(&self.actor_id()) => {
+                let document = self.fetch(actor)?;
+                let inbox = document["inbox"].as_str().ok_or("Actor without inbox")?;
+                let shared_inbox = document["endpoints"]["sharedInbox"].as_str();
+
+                conn.execute("
+                    INSERT INTO followers (actor, inbox, shared_inbox, date)
+                    VALUES (?, ?, ?, strftime('%s', 'now'))
+                    ON CONFLICT(actor) DO UPDATE SET
+                        inbox=excluded.inbox,
+                        shared_inbox=excluded.shared_inbox
+                ", params![actor, inbox, shared_inbox])?;
+
+                let accept = json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "id": format!("{}#accept-{}", self.actor_id(), Utc::now().timestamp_millis()),
+                    "type": "Accept",
+                    "actor": self.actor_id(),
+                    "object": activity,
+                });
+
+                self.deliver(inbox, &accept)?;
+            }
+
+            Some("Undo") if activity["object"]["type"].as_str() == Some("Follow") => {
+                conn.execute("DELETE FROM followers WHERE actor=?", params![actor])?;
+            }
+
+            Some("Delete") if activity["object"].as_str() == Some(actor) => {
+                conn.execute("DELETE FROM followers WHERE actor=?", params![actor])?;
+            }
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn inboxes(&self, conn: &Connection) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut stmt = conn.prepare("
+            SELECT
+                COALESCE(shared_inbox, inbox)
+            FROM
+                followers
+        ")?;
+
+        let inboxes: HashSet<String> = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(inboxes.into_iter().collect())
+    }
+}
+
+// Keys, actors and inboxes are named by other servers. The client connects
+// only to public addresses, the same as for webmentions, and federation
+// happens over https alone.
+fn check_remote(url: &str) -> Result<(), Box<dyn Error>> {
+    if Url::parse(url)?.scheme() != "https" {
+        return Err(format!("{} is not an https URL", url).into());
+    }
+
+    Ok(())
+}
+
+fn activity_json(value: &Value) -> HttpResponse {
+    vary_accept(HttpResponse::Ok()
+        .content_type(ACTIVITY_JSON)
+        .body(value.to_string()))
+}
+
+// Article URLs answer with a page or an object depending on Accept, so
+// shared caches have to keep the two apart.
+pub fn vary_accept(mut response: HttpResponse) -> HttpResponse {
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("Accept"));
+    response
+}
+
+pub fn wants_activity(req: &HttpRequest) -> bool {
+    req.headers().get_all(header::ACCEPT)
+        .filter_map(|accept| accept.to_str().ok())
+        .any(|accept| accept.contains(ACTIVITY_JSON) || accept.contains("application/ld+json"))
+}
+
+pub async fn webfinger(req: HttpRequest,
+                       state: web::Data<State<'_>>,
+                       query: web::Query<WebfingerQuery>) -> HttpResponse {
+    let federation = match &state.activitypub {
+        Some(federation) => federation,
+        None => return error_404(req, state).await,
+    };
+
+    let account = format!("acct:{}@{}", federation.settings.username, state.config.host);
+
+    if !query.resource.eq_ignore_ascii_case(&account) && query.resource != federation.actor_id() {
+        return error_404(req, state).await;
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/jrd+json")
+        .body(json!({
+            "subject": account,
+            "aliases": [federation.actor_id()],
+            "links": [{
+                "rel": "self",
+                "type": ACTIVITY_JSON,
+                "href": federation.actor_id(),
+            }],
+        }).to_string())
+}
+
+pub async fn actor(req: HttpRequest,
+                   state: web::Data<State<'_>>) -> HttpResponse {
+    match &state.activitypub {
+        Some(federation) => activity_json(&try_500!(federation.actor(), state, req)),
+        None => error_404(req, state).await,
+    }
+}
+
+pub async fn outbox(req: HttpRequest,
+                    state: web::Data<State<'_>>) -> HttpResponse {
+    try_500!(outbox_inner(req, state).await, state, req)
+}
+
+pub async fn followers(req: HttpRequest,
+                       state: web::Data<State<'_>>) -> HttpResponse {
+    try_500!(followers_inner(req, state).await, state, req)
+}
+
+pub async fn inbox(req: HttpRequest,
+                   state: web::Data<State<'_>>,
+                   body: web::Bytes) -> HttpResponse {
+    try_500!(inbox_inner(req, state, body).await, state, req)
+}
+
+pub fn article_object(state: &State<'_>, post: &Post) -> Option<HttpResponse> {
+    let federation = state.activitypub.as_ref()?;
+    let mut object = federation.article(post);
+
+    object["@context"] = json!("https://www.w3.org/ns/activitystreams");
+
+    Some(activity_json(&object))
+}
+
+async fn outbox_inner(req: HttpRequest,
+                      state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
+    let federation = match &state.activitypub {
+        Some(federation) => federation,
+        None => return Ok(error_404(req.clone(), state.clone()).await),
+    };
+
+    let items: Vec<Value> = pages::published_articles(&state.conn)?.iter()
+        .map(|post| federation.activity("Create", federation.article(post)))
+        .collect();
+
+    Ok(activity_json(&json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": federation.url("/actor/outbox"),
+        "type": "OrderedCollection",
+        "totalItems": items.len(),
+        "orderedItems": items,
+    })))
+}
+
+// Only the number of followers is public, not who they are.
+async fn followers_inner(req: HttpRequest,
+                         state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
+    let federation = match &state.activitypub {
+        Some(federation) => federation,
+        None => return Ok(error_404(req.clone(), state.clone()).await),
+    };
+
+    let count: i64 = state.conn.query_row("SELECT COUNT(*) FROM followers", [], |row| row.get(0))?;
+
+    Ok(activity_json(&json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": federation.url("/actor/followers"),
+        "type": "OrderedCollection",
+        "totalItems": count,
+    })))
+}
+
+async fn inbox_inner(req: HttpRequest,
+                     state: web::Data<State<'_>>,
+                     body: web::Bytes) -> Result<HttpResponse, Box<dyn Error>> {
+    let federation = match &state.activitypub {
+        Some(federation) => federation.clone(),
+        None => return Ok(error_404(req.clone(), state.clone()).await),
+    };
+
+    let activity: Value = match serde_json::from_slice(&body) {
+        Ok(activity) => activity,
+        Err(_) => return Ok(HttpResponse::BadRequest().body("Body is not JSON")),
+    };
+
+    let method = req.method().as_str().to_owned();
+
+    let target = match req.uri().query() {
+        Some(query) => format!("{}?{}", req.path(), query),
+        None => req.path().to_owned(),
+    };
+
+    let headers: Vec<(String, String)> = req.headers().iter()
+        .filter_map(|(name, value)| Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned())))
+        .collect();
+
+    let database = state.config.database.clone();
+
+    // Checking the signature and answering a follow both need other servers.
+    let result = web::block(move || -> Result<(), String> {
+        let signer = federation.verify(&method, &target, &headers, &body)
+            .map_err(|e| format!("unauthorized: {}", e))?;
+
+        let conn = Connection::open(&database).map_err(|e| e.to_string())?;
+
+        federation.handle(&conn, &signer, &activity).map_err(|e| e.to_string())
+    }).await;
+
+    match result {
+        Ok(()) => Ok(HttpResponse::Accepted().finish()),
+
+        Err(BlockingError::Error(e)) if e.starts_with("unauthorized: ") => {
+            eprintln!("Inbox activity rejected: {}", e);
+            Ok(HttpResponse::Unauthorized().finish())
+        }
+
+        Err(BlockingError::Error(e)) => Err(e.into()),
+        Err(BlockingError::Canceled) => Err("Inbox handling was canceled".into()),
+    }
+}
+
+// Sends Create for new articles and Update for edited ones to followers.
+pub struct Delivery(pub Arc<Federation>);
+
+impl Listener for Delivery {
+    fn name(&self) -> &'static str {
+        "activitypub"
+    }
+
+    fn notify(&self, conn: &Connection, events: &[Event]) -> Result<(), Box<dyn Error>> {
+        let federation = &self.0;
+        let inboxes = federation.inboxes(conn)?;
+
+        if inboxes.is_empty() {
+            return Ok(());
+        }
+
+        for event in events {
+            let kind = match event.change {
+                Change::Published => "Create",
+                Change::Updated => "Update",
+            };
+
+            let activity = federation.activity(kind, federation.article(&event.post));
+
+            for inbox in &inboxes {
+                if let Err(e) = federation.deliver(inbox, &activity) {
+                    eprintln!("Delivering {} of {} to {} failed: {}", kind, event.post.link, inbox, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::http::{ HttpClient, Request };
+    use crate::http::fake::FakeClient;
+    use super::Federation;
+
+    const INBOX: &str = "https://example.org/actor/inbox";
+    const ACTIVITY: &str = r#"{"type":"Follow"}"#;
+
+    fn federation(host: &str, client: Arc<dyn HttpClient>) -> Federation {
+        let config: Config = serde_json::from_value(json!({
+            "host": host,
+            "database": "",
+            "templates": "",
+            "token": "",
+            "geoip_db_file": "",
+        })).unwrap();
+
+        let settings = serde_json::from_value(json!({
+            "username": "blog",
+            "name": "Blog",
+        })).unwrap();
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        Federation { config: Arc::new(config), settings, key, client }
+    }
+
+    // Headers as they arrive at the inbox, Host included.
+    fn headers(request: Request) -> Vec<(String, String)> {
+        let mut headers = request.headers;
+
+        headers.push(("Host".to_owned(), "example.org".to_owned()));
+        headers
+    }
+
+    fn signed_by(sender: &Federation) -> Vec<(String, String)> {
+        let request = Request::post(INBOX, super::ACTIVITY_JSON, ACTIVITY.as_bytes().to_vec());
+
+        headers(sender.sign(request).unwrap())
+    }
+
+    #[test]
+    fn valid_signature() {
+        let sender = federation("remote.net", Arc::new(FakeClient::default()));
+        let actor = sender.actor().unwrap().to_string();
+        let client = FakeClient::default().page("https://remote.net/actor", "https://remote.net/actor", &[], &actor);
+        let inbox = federation("example.org", Arc::new(client));
+
+        let signer = inbox.verify("POST", "/actor/inbox", &signed_by(&sender), ACTIVITY.as_bytes()).unwrap();
+
+        assert_eq!(signer, "https://remote.net/actor");
+    }
+
+    #[test]
+    fn digest_mismatch() {
+        let sender = federation("remote.net", Arc::new(FakeClient::default()));
+        let actor = sender.actor().unwrap().to_string();
+        let client = FakeClient::default().page("https://remote.net/actor", "https://remote.net/actor", &[], &actor);
+        let inbox = federation("example.org", Arc::new(client));
+
+        let error = inbox.verify("POST", "/actor/inbox", &signed_by(&sender), br#"{"type":"Delete"}"#).unwrap_err();
+
+        assert_eq!(error.to_string(), "Digest does not match the body");
+    }
+
+    #[test]
+    fn missing_covered_header() {
+        let sender = federation("remote.net", Arc::new(FakeClient::default()));
+        let inbox = federation("example.org", Arc::new(FakeClient::default()));
+
+        // Without a body nothing signs the digest.
+        let headers = headers(sender.sign(Request::get(INBOX)).unwrap());
+        let error = inbox.verify("POST", "/actor/inbox", &headers, ACTIVITY.as_bytes()).unwrap_err();
+
+        assert_eq!(error.to_string(), "Signature does not cover digest");
+    }
+
+    #[test]
+    fn foreign_owner_key() {
+        let victim = federation("remote.net", Arc::new(FakeClient::default()));
+        let sender = federation("evil.net", Arc::new(FakeClient::default()));
+        let mut actor = sender.actor().unwrap();
+
+        actor["publicKey"]["owner"] = json!("https://remote.net/actor");
+
+        let client = FakeClient::default()
+            .page("https://evil.net/actor", "https://evil.net/actor", &[], &actor.to_string())
+            .page("https://remote.net/actor", "https://remote.net/actor", &[], &victim.actor().unwrap().to_string());
+        let inbox = federation("example.org", Arc::new(client));
+
+        let error = inbox.verify("POST", "/actor/inbox", &signed_by(&sender), ACTIVITY.as_bytes()).unwrap_err();
+
+        assert_eq!(error.to_string(), "Key https://evil.net/actor#main-key is foreign to https://remote.net/actor");
+    }
+
+    #[test]
+    fn unclaimed_key() {
+        let victim = federation("remote.net", Arc::new(FakeClient::default()));
+        let sender = federation("remote.net", Arc::new(FakeClient::default()));
+        let mut key = sender.actor().unwrap()["publicKey"].clone();
+
+        // Another account on the same server publishes a key in the victim's name.
+        key["id"] = json!("https://remote.net/mallory/key");
+
+        let client = FakeClient::default()
+            .page("https://remote.net/mallory/key", "https://remote.net/mallory/key", &[], &key.to_string())
+            .page("https://remote.net/actor", "https://remote.net/actor", &[], &victim.actor().unwrap().to_string());
+        let inbox = federation("example.org", Arc::new(client));
+
+        let mut headers = signed_by(&sender);
+
+        for (name, value) in headers.iter_mut() {
+            if name == "Signature" {
+                *value = value.replace("https://remote.net/actor#main-key", "https://remote.net/mallory/key");
+            }
+        }
+
+        let error = inbox.verify("POST", "/actor/inbox", &headers, ACTIVITY.as_bytes()).unwrap_err();
+
+        assert_eq!(error.to_string(), "https://remote.net/actor does not claim key https://remote.net/mallory/key");
+    }
+}
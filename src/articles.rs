@@ -0,0 +1,235 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::env;
+use std::fs;
+use std::error::Error;
+use std::io::Write;
+use std::process::Command;
+use rusqlite::{ params, Connection, OptionalExtension };
+
+// Article management for the command line. Hidden articles live in a table
+// of their own and are only reachable by their link, unlisted ones are in
+// `articles` with dnshow set.
+#[derive(Clone, Copy)]
+pub enum Table {
+    Articles,
+    Hidden,
+}
+
+impl Table {
+    pub fn new(hidden: bool) -> Table {
+        if hidden { Table::Hidden } else { Table::Articles }
+    }
+
+    pub fn hidden(self) -> bool {
+        matches!(self, Table::Hidden)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Articles => "articles",
+            Table::Hidden => "hidden_articles",
+        }
+    }
+}
+
+pub struct Listed {
+    pub link: String,
+    pub name: String,
+    pub date: i64,
+    pub unlisted: bool,
+}
+
+pub fn exists(conn: &Connection, table: Table, link: &str) -> rusqlite::Result<bool> {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {} WHERE link=?", table.name()),
+                   params![link],
+                   |row| row.get(0)).map(|count: i64| count > 0)
+}
+
+pub fn create(conn: &Connection,
+              table: Table,
+              link: &str,
+              name: &str,
+              text: &str,
+              short_text: Option<&str>,
+              unlisted: bool) -> Result<(), Box<dyn Error>> {
+    if exists(conn, table, link)? {
+        return Err(format!("Article {} already exists", link).into());
+    }
+
+    match table {
+        Table::Articles => conn.execute("
+            INSERT INTO articles (link, name, text, short_text, date, lastmod, dnshow)
+            VALUES (?, ?, ?, ?, strftime('%s', 'now'), 0, ?)
+        ", params![link, name, text, short_text, unlisted])?,
+
+        Table::Hidden => conn.execute("
+            INSERT INTO hidden_articles (link, name, text, date, lastmod)
+            VALUES (?, ?, ?, strftime('%s', 'now'), 0)
+        ", params![link, name, text])?,
+    };
+
+    Ok(())
+}
+
+pub fn text(conn: &Connection, table: Table, link: &str) -> Result<String, Box<dyn Error>> {
+    let text: Option<String> = conn.query_row(&format!("SELECT text FROM {} WHERE link=?", table.name()),
+                                              params![link],
+                                              |row| row.get(0)).optional()?;
+
+    text.ok_or_else(|| format!("There is no article {}", link).into())
+}
+
+fn update(conn: &Connection, table: Table, link: &str, set: &str, value: &dyn rusqlite::ToSql) -> Result<(), Box<dyn Error>> {
+    let updated = conn.execute(&format!("UPDATE {} SET {} WHERE link=?", table.name(), set),
+                               params![value, link])?;
+
+    if updated == 0 {
+        return Err(format!("There is no article {}", link).into());
+    }
+
+    Ok(())
+}
+
+pub fn set_text(conn: &Connection, table: Table, link: &str, text: &str) -> Result<(), Box<dyn Error>> {
+    update(conn, table, link, "text=?, lastmod=strftime('%s', 'now')", &text)
+}
+
+pub fn set_name(conn: &Connection, table: Table, link: &str, name: &str) -> Result<(), Box<dyn Error>> {
+    update(conn, table, link, "name=?, lastmod=strftime('%s', 'now')", &name)
+}
+
+pub fn set_short_text(conn: &Connection, link: &str, short_text: &str) -> Result<(), Box<dyn Error>> {
+    update(conn, Table::Articles, link, "short_text=?, lastmod=strftime('%s', 'now')", &short_text)
+}
+
+pub fn set_unlisted(conn: &Connection, link: &str, unlisted: bool) -> Result<(), Box<dyn Error>> {
+    update(conn, Table::Articles, link, "dnshow=?", &unlisted)
+}
+
+// The language of an article and the key shared by its translations.
+pub fn translation(conn: &Connection, table: Table, link: &str) -> rusqlite::Result<Option<(String, String)>> {
+    conn.query_row("
+        SELECT
+            lang, key
+        FROM
+            translations
+        WHERE
+            link=? AND hidden=?
+    ", params![link, table.hidden()], |row| Ok((row.get(0)?, row.get(1)?))).optional()
+}
+
+pub fn set_translation(conn: &Connection,
+                       table: Table,
+                       link: &str,
+                       translation: Option<(&str, &str)>) -> rusqlite::Result<()> {
+    match translation {
+        Some((lang, key)) => conn.execute("
+            INSERT INTO translations (link, hidden, lang, key)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(link, hidden) DO UPDATE SET lang=excluded.lang, key=excluded.key
+        ", params![link, table.hidden(), lang, key])?,
+
+        None => conn.execute("DELETE FROM translations WHERE link=? AND hidden=?",
+                             params![link, table.hidden()])?,
+    };
+
+    Ok(())
+}
+
+pub fn delete(conn: &Connection, table: Table, link: &str) -> Result<(), Box<dyn Error>> {
+    let deleted = conn.execute(&format!("DELETE FROM {} WHERE link=?", table.name()), params![link])?;
+
+    if deleted == 0 {
+        return Err(format!("There is no article {}", link).into());
+    }
+
+    conn.execute("DELETE FROM tags WHERE link=? AND hidden=?", params![link, table.hidden()])?;
+    conn.execute("DELETE FROM imported WHERE link=? AND hidden=?", params![link, table.hidden()])?;
+    set_translation(conn, table, link, None)?;
+
+    // SQLite leaves foreign keys unenforced, so ON DELETE CASCADE of the
+    // schema never fires. Comments and mentions only belong to public
+    // articles.
+    if !table.hidden() {
+        conn.execute("DELETE FROM comments WHERE article=?", params![link])?;
+        conn.execute("DELETE FROM webmentions WHERE article=?", params![link])?;
+    }
+
+    Ok(())
+}
+
+pub fn list(conn: &Connection, table: Table) -> Result<Vec<Listed>, Box<dyn Error>> {
+    let unlisted = match table {
+        Table::Articles => "dnshow",
+        Table::Hidden => "0",
+    };
+
+    let mut stmt = conn.prepare(&format!("
+        SELECT
+            link, name, date, {}
+        FROM
+            {}
+        ORDER BY
+            date DESC
+    ", unlisted, table.name()))?;
+
+    let articles = stmt
+        .query_map([], |row| Ok(Listed {
+            link: row.get(0)?,
+            name: row.get(1)?,
+            date: row.get(2)?,
+            unlisted: row.get(3)?,
+        }))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(articles)
+}
+
+// Opens $VISUAL or $EDITOR on a copy of the text and returns what was saved.
+pub fn edit_in_editor(link: &str, text: &str) -> Result<String, Box<dyn Error>> {
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_owned());
+    let name: String = link.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+
+    // A name of its own, created exclusively, so nobody else sharing the
+    // temporary directory can put a file or a link there first.
+    let mut file = tempfile::Builder::new()
+        .prefix(&format!("website-{}-", name))
+        .suffix(".html")
+        .tempfile()?;
+
+    file.write_all(text.as_bytes())?;
+    file.flush()?;
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(file.path())
+        .status();
+
+    let edited = fs::read_to_string(file.path());
+
+    file.close()?;
+
+    if !status?.success() {
+        return Err("The editor failed, the article is left as it was".into());
+    }
+
+    Ok(edited?)
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::HashMap;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::http::{ HeaderValue, header };
+use openssl::sha::sha256;
+use tera::{ Function, Value };
+
+use crate::compression::serve_static;
+use crate::state::State;
+use crate::errors::*;
+
+pub const STATIC_DIR: &str = "static";
+
+const PRECOMPRESSED_SUFFIXES: &[&str] = &[".br", ".gz", ".zst"];
+
+pub struct AssetManifest {
+    hashes: HashMap<String, String>,
+}
+
+impl AssetManifest {
+    pub fn build(root: &Path) -> io::Result<AssetManifest> {
+        let mut hashes = HashMap::new();
+
+        add_dir(root, "", &mut hashes)?;
+
+        Ok(AssetManifest { hashes })
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        let path = path.trim_start_matches('/');
+
+        match self.hashes.get(path) {
+            Some(hash) => format!("/assets/{}/{}", hash, encode_path(path)),
+            None => format!("/{}", encode_path(path)),
+        }
+    }
+
+    // Paths relative to the static directory, with their hashes.
+    pub fn files(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.hashes.iter().map(|(path, hash)| (path.as_str(), hash.as_str()))
+    }
+
+    pub fn matches(&self, path: &str, hash: &str) -> bool {
+        self.hashes.get(path).map(|known| known == hash).unwrap_or(false)
+    }
+}
+
+fn add_dir(dir: &Path,
+           prefix: &str,
+           hashes: &mut HashMap<String, String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = format!("{}{}", prefix, name);
+
+        if name.starts_with('.') {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            add_dir(&entry.path(), &format!("{}/", path), hashes)?;
+        } else if !PRECOMPRESSED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+            let hash = sha256(&fs::read(entry.path())?)[..8].iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+
+            hashes.insert(path, hash);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+pub struct AssetUrl(pub Arc<AssetManifest>);
+
+impl Function for AssetUrl {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let path = args.get("path")
+            .and_then(Value::as_str)
+            .ok_or("asset_url requires a string `path` argument")?;
+
+        Ok(Value::String(self.0.url(path)))
+    }
+
+    // The URL is percent-encoded, so it is safe inside attributes.
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+pub async fn asset(req: HttpRequest,
+                   state: web::Data<State<'_>>,
+                   path: web::Path<(String, String)>) -> HttpResponse {
+    let (hash, path) = path.into_inner();
+
+    let mut response = match serve_static(&req, Path::new(STATIC_DIR), &path) {
+        Some(response) => try_500!(response, state, req),
+        None => return error_404(req, state).await,
+    };
+
+    // A stale hash still gets the current file, but it must not be pinned
+    // in caches under that URL.
+    if state.assets.matches(&path, &hash) {
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=31536000, immutable")
+        );
+    }
+
+    response
+}
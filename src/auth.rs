@@ -17,11 +17,11 @@
 
 use std::error::Error;
 use serde::Deserialize;
-use tera::Context;
 use actix_web::{ HttpRequest, HttpMessage, HttpResponse, cookie::Cookie, web, http::header };
 
 use crate::errors::*;
 use crate::state::State;
+use crate::users;
 
 pub struct Auth {
     token: String,
@@ -42,20 +42,21 @@ impl Auth {
         }
     }
 
-    pub fn auth(&mut self, token: String) -> bool {
-        if token == self.token {
+    // `known` tells that the token belongs to one of the users.
+    pub fn auth(&mut self, token: String, known: bool) -> bool {
+        if known || token == self.token {
             self.cookie.set_value(token);
             return true;
         }
 
-        return false;
+        false
     }
 
     pub fn deauth(&self, response: &mut HttpResponse) -> Result<(), actix_web::http::Error> {
         response.add_cookie(&Cookie::named("auth"))
     }
 
-    pub fn cookie(&self) -> &Cookie {
+    pub fn cookie(&self) -> &Cookie<'_> {
         &self.cookie
     }
 
@@ -84,9 +85,10 @@ pub async fn auth_submit(req: HttpRequest,
         .header("Location", "/")
         .finish();
 
+    let known = try_500!(users::known_token(&state.conn, &form.token), state, req);
     let mut auth = try_500!(state.auth.write(), state, req);
 
-    if auth.auth(form.token.clone()) {
+    if auth.auth(form.token.clone(), known) {
         try_500!(response.add_cookie(auth.cookie()), state, req);
     }
 
@@ -105,10 +107,7 @@ pub async fn deauth(req: HttpRequest,
 
 async fn auth_inner(req: HttpRequest,
                     state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
-    let mut context = Context::new();
-    let auth = state.auth.read().map_err(MyError::from)?;
-
-    context.insert("authorized", &auth.authorized(&req));
+    let context = state.context(&req)?;
 
     Ok(HttpResponse::Ok().body(state.tera.render("auth.html", &context)?))
 }
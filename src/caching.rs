@@ -0,0 +1,193 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::{ SystemTime, UNIX_EPOCH };
+use actix_web::{ web::Bytes, HttpMessage, HttpRequest, HttpResponse };
+use actix_web::http::header::{ self, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch };
+use openssl::sha::sha256;
+
+use crate::post::{ Post, PostDate };
+
+pub fn respond(req: &HttpRequest,
+               body: Bytes,
+               etag: &EntityTag,
+               modified: Option<&PostDate>,
+               authorized: bool,
+               max_age: u64) -> HttpResponse {
+    let last_modified = modified.map(|date| HttpDate::from(SystemTime::from(date.0)));
+
+    let not_modified = not_modified(req, etag, last_modified);
+
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+
+    response.header(header::ETAG, etag.to_string());
+    response.header(header::VARY, "Cookie");
+
+    if let Some(last_modified) = last_modified {
+        response.header(header::LAST_MODIFIED, last_modified.to_string());
+    }
+
+    if authorized {
+        response.header(header::CACHE_CONTROL, "private, no-cache");
+    } else {
+        response.header(header::CACHE_CONTROL, format!("public, max-age={}", max_age));
+    }
+
+    if not_modified {
+        response.finish()
+    } else {
+        response.body(body)
+    }
+}
+
+pub fn etag(body: &[u8]) -> EntityTag {
+    EntityTag::strong(hash(body))
+}
+
+pub fn modified(post: &Post) -> Option<&PostDate> {
+    post.lastmod.as_ref().or(post.date.as_ref())
+}
+
+pub fn newest<'a, I: IntoIterator<Item = &'a Post>>(posts: I) -> Option<&'a PostDate> {
+    latest(posts.into_iter().filter_map(modified))
+}
+
+pub fn latest<'a, I: IntoIterator<Item = &'a PostDate>>(dates: I) -> Option<&'a PostDate> {
+    dates.into_iter().max_by_key(|date| date.0)
+}
+
+// If-None-Match takes precedence over If-Modified-Since as in RFC 7232.
+fn not_modified(req: &HttpRequest,
+                etag: &EntityTag,
+                last_modified: Option<HttpDate>) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => return true,
+        Some(IfNoneMatch::Items(items)) => {
+            return items.iter().any(|item| item.weak_eq(etag));
+        }
+
+        None => {}
+    }
+
+    if let (Some(last_modified), Some(IfModifiedSince(since))) =
+        (last_modified, req.get_header::<IfModifiedSince>())
+    {
+        let modified = SystemTime::from(last_modified).duration_since(UNIX_EPOCH);
+        let since = SystemTime::from(since).duration_since(UNIX_EPOCH);
+
+        if let (Ok(modified), Ok(since)) = (modified, since) {
+            return modified.as_secs() <= since.as_secs();
+        }
+    }
+
+    false
+}
+
+fn hash(body: &[u8]) -> String {
+    sha256(body)[..16].iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+    use actix_web::test::TestRequest;
+    use actix_web::http::header::{ EntityTag, HttpDate };
+
+    use super::not_modified;
+
+    fn date(secs: u64) -> HttpDate {
+        HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn etag() -> EntityTag {
+        EntityTag::strong("abc".to_owned())
+    }
+
+    #[test]
+    fn matching_etag() {
+        let req = TestRequest::default()
+            .header("If-None-Match", "\"xyz\", \"abc\"")
+            .to_http_request();
+
+        assert!(not_modified(&req, &etag(), None));
+    }
+
+    #[test]
+    fn any_etag() {
+        let req = TestRequest::default()
+            .header("If-None-Match", "*")
+            .to_http_request();
+
+        assert!(not_modified(&req, &etag(), None));
+    }
+
+    #[test]
+    fn etag_takes_precedence_over_date() {
+        let req = TestRequest::default()
+            .header("If-None-Match", "\"xyz\"")
+            .header("If-Modified-Since", date(2000).to_string())
+            .to_http_request();
+
+        assert!(!not_modified(&req, &etag(), Some(date(1000))));
+
+        let req = TestRequest::default()
+            .header("If-None-Match", "\"abc\"")
+            .header("If-Modified-Since", date(1000).to_string())
+            .to_http_request();
+
+        assert!(not_modified(&req, &etag(), Some(date(2000))));
+    }
+
+    #[test]
+    fn modified_since() {
+        let req = TestRequest::default()
+            .header("If-Modified-Since", date(1000).to_string())
+            .to_http_request();
+
+        assert!(not_modified(&req, &etag(), Some(date(1000))));
+        assert!(not_modified(&req, &etag(), Some(date(999))));
+        assert!(!not_modified(&req, &etag(), Some(date(1001))));
+    }
+
+    #[test]
+    fn without_validators() {
+        let req = TestRequest::default().to_http_request();
+
+        assert!(!not_modified(&req, &etag(), Some(HttpDate::from(SystemTime::now()))));
+
+        let req = TestRequest::default()
+            .header("If-Modified-Since", date(1000).to_string())
+            .to_http_request();
+
+        assert!(!not_modified(&req, &etag(), None));
+    }
+
+    #[test]
+    fn invalid_date_is_ignored() {
+        let req = TestRequest::default()
+            .header("If-Modified-Since", "yesterday")
+            .to_http_request();
+
+        assert!(!not_modified(&req, &etag(), Some(date(1000))));
+    }
+}
@@ -0,0 +1,430 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::error::Error;
+use std::io::{ self, Read };
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use clap::{ Parser, Subcommand };
+use rusqlite::{ Connection, OpenFlags };
+
+use crate::articles::{ self, Table };
+use crate::assets::{ AssetManifest, STATIC_DIR };
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::i18n::Catalog;
+use crate::post::PostDate;
+use crate::tls::Certificates;
+use crate::{ content, export, mail, schema, templates, users };
+
+#[derive(Parser)]
+#[command(version, about = "Сайт Миры Странной")]
+pub struct Cli {
+    /// Configuration file
+    #[arg(long, global = true, default_value = "config.json")]
+    pub config: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the server, which is also what happens without a command
+    Serve,
+    /// Apply pending database migrations
+    Migrate,
+    /// Create, change and list articles
+    #[command(subcommand)]
+    Article(ArticleCommand),
+    /// Manage users who can sign in
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage sign in tokens
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Check the configuration and everything it points to
+    CheckConfig,
+    /// Write compressed copies of static files next to them
+    Precompress {
+        #[arg(default_value = STATIC_DIR)]
+        dir: PathBuf,
+    },
+    /// Write a static mirror of the site
+    Export {
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ArticleCommand {
+    /// Create an article, its text is taken from a file or written in $EDITOR
+    New {
+        link: String,
+        #[arg(long)]
+        title: String,
+        /// File with the text, HTML or markdown
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// File with the text shown in the list of articles
+        #[arg(long)]
+        short_text: Option<PathBuf>,
+        /// Put it among hidden articles, reachable only by the link
+        #[arg(long)]
+        hidden: bool,
+        /// Do not show it in the list of articles
+        #[arg(long)]
+        unlisted: bool,
+        /// Language of the text, like en
+        #[arg(long)]
+        lang: Option<String>,
+        /// Key shared with the translations of the article, the link by default
+        #[arg(long, requires = "lang")]
+        translation: Option<String>,
+    },
+    /// Change an article, without options its text is opened in $EDITOR
+    Edit {
+        link: String,
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long)]
+        short_text: Option<PathBuf>,
+        #[arg(long)]
+        hidden: bool,
+        #[arg(long, conflicts_with = "listed")]
+        unlisted: bool,
+        #[arg(long)]
+        listed: bool,
+        #[arg(long)]
+        lang: Option<String>,
+        #[arg(long)]
+        translation: Option<String>,
+    },
+    /// List articles
+    List {
+        #[arg(long)]
+        hidden: bool,
+    },
+    /// Delete an article
+    Delete {
+        link: String,
+        #[arg(long)]
+        hidden: bool,
+    },
+    /// Import files with front matter, or a directory of them. A file
+    /// without front matter replaces the text of an article, `-` reads
+    /// standard input
+    Import {
+        file: PathBuf,
+        /// Defaults to the file name without extension
+        #[arg(long)]
+        link: Option<String>,
+        #[arg(long)]
+        hidden: bool,
+        /// Delete articles imported from the directory before whose files are gone
+        #[arg(long)]
+        prune: bool,
+    },
+    /// Write the text of an article to a file or standard output, without a
+    /// link every article goes to a directory as a file with front matter
+    Export {
+        link: Option<String>,
+        #[arg(long, required_unless_present = "link")]
+        out: Option<PathBuf>,
+        #[arg(long)]
+        hidden: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Add a user and print their first token
+    Add {
+        name: String,
+    },
+    /// Remove a user, their tokens stop working
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Print a new token, for the user if given, otherwise one for the config
+    Create {
+        user: Option<String>,
+    },
+}
+
+// Everything but serving, which stays in main.
+pub fn run(config_path: &str, command: Command) -> Result<(), Box<dyn Error>> {
+    if let Command::CheckConfig = command {
+        return check_config(config_path);
+    }
+
+    if let Command::Token(TokenCommand::Create { user: None }) = command {
+        println!("{}", users::generate_token()?);
+        return Ok(());
+    }
+
+    let config = Arc::new(Config::read_from_file(config_path)?);
+    let mut conn = Connection::open(&config.database)?;
+
+    let applied = schema::migrate(&mut conn)?;
+
+    match command {
+        Command::Migrate => println!("{} migrations applied", applied),
+        Command::Article(command) => article(&mut conn, command)?,
+        Command::User(UserCommand::Add { name }) => println!("{}", users::add(&conn, &name)?),
+        Command::User(UserCommand::Remove { name }) => users::remove(&conn, &name)?,
+        Command::Token(TokenCommand::Create { user: Some(user) }) => println!("{}", users::create_token(&conn, &user)?),
+        Command::Export { out } => export::export(config, &out)?,
+
+        // Handled above, or by main before the config is read.
+        Command::CheckConfig
+            | Command::Token(TokenCommand::Create { user: None })
+            | Command::Serve
+            | Command::Precompress { .. } => {}
+    }
+
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<String, Box<dyn Error>> {
+    if path == Path::new("-") {
+        let mut text = String::new();
+
+        io::stdin().read_to_string(&mut text)?;
+
+        Ok(text)
+    } else {
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+fn article(conn: &mut Connection, command: ArticleCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        ArticleCommand::New { link, title, file, short_text, hidden, unlisted, lang, translation } => {
+            let text = match file {
+                Some(file) => read(&file)?,
+                None => articles::edit_in_editor(&link, "")?,
+            };
+
+            let short_text = short_text.map(|file| read(&file)).transpose()?;
+
+            if hidden && (short_text.is_some() || unlisted) {
+                return Err("Hidden articles have neither short text nor listing".into());
+            }
+
+            articles::create(conn, Table::new(hidden), &link, &title, &text, short_text.as_deref(), unlisted)?;
+
+            if let Some(lang) = lang {
+                let key = translation.unwrap_or_else(|| link.clone());
+
+                articles::set_translation(conn, Table::new(hidden), &link, Some((&lang, &key)))?;
+            }
+        }
+
+        ArticleCommand::Edit { link, title, short_text, hidden, unlisted, listed, lang, translation } => {
+            let table = Table::new(hidden);
+
+            if hidden && (short_text.is_some() || unlisted || listed) {
+                return Err("Hidden articles have neither short text nor listing".into());
+            }
+
+            if title.is_none() && short_text.is_none() && !unlisted && !listed
+                && lang.is_none() && translation.is_none() {
+                let text = articles::text(conn, table, &link)?;
+                let edited = articles::edit_in_editor(&link, &text)?;
+
+                if edited != text {
+                    articles::set_text(conn, table, &link, &edited)?;
+                }
+            }
+
+            if let Some(title) = title {
+                articles::set_name(conn, table, &link, &title)?;
+            }
+
+            if let Some(short_text) = short_text {
+                articles::set_short_text(conn, &link, &read(&short_text)?)?;
+            }
+
+            if unlisted || listed {
+                articles::set_unlisted(conn, &link, unlisted)?;
+            }
+
+            if lang.is_some() || translation.is_some() {
+                if !articles::exists(conn, table, &link)? {
+                    return Err(format!("There is no article {}", link).into());
+                }
+
+                let old = articles::translation(conn, table, &link)?;
+
+                let lang = lang.or_else(|| old.as_ref().map(|(lang, _)| lang.clone()))
+                    .ok_or("A translation needs its lang")?;
+
+                let key = translation.or_else(|| old.map(|(_, key)| key))
+                    .unwrap_or_else(|| link.clone());
+
+                articles::set_translation(conn, table, &link, Some((&lang, &key)))?;
+            }
+        }
+
+        ArticleCommand::List { hidden } => {
+            for article in articles::list(conn, Table::new(hidden))? {
+                // Articles without a date keep zero in the database.
+                let date = PostDate::from_timestamp(article.date)
+                    .map(|date| date.0.format("%Y-%m-%d").to_string())
+                    .unwrap_or_else(|| "-".to_owned());
+
+                println!("{}\t{}\t{}{}",
+                         date,
+                         article.link,
+                         article.name,
+                         if article.unlisted { " (unlisted)" } else { "" });
+            }
+        }
+
+        ArticleCommand::Delete { link, hidden } => articles::delete(conn, Table::new(hidden), &link)?,
+
+        ArticleCommand::Import { file, link, hidden, prune } => {
+            let front_matter = file.is_dir() || (file != Path::new("-") && {
+                content::parse(&fs::read_to_string(&file)?)
+                    .map_err(|e| format!("{}: {}", file.display(), e))?
+                    .is_some()
+            });
+
+            if front_matter {
+                if link.is_some() || hidden {
+                    return Err("Links and hiding of imported articles come from their front matter".into());
+                }
+
+                let report = content::import(conn, &file, prune, false)?;
+
+                for (status, links) in &[("created", &report.created),
+                                         ("updated", &report.updated),
+                                         ("unchanged", &report.unchanged),
+                                         ("deleted", &report.deleted)] {
+                    for link in links.iter() {
+                        println!("{}\t{}", status, link);
+                    }
+                }
+
+                println!("{} created, {} updated, {} unchanged, {} deleted",
+                         report.created.len(),
+                         report.updated.len(),
+                         report.unchanged.len(),
+                         report.deleted.len());
+
+                return Ok(());
+            }
+
+            if prune {
+                return Err("Only a directory can be pruned".into());
+            }
+
+            let link = match link {
+                Some(link) => link,
+                None => file.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .filter(|stem| stem != "-")
+                    .ok_or("The link can not be taken from the file name, pass --link")?,
+            };
+
+            articles::set_text(conn, Table::new(hidden), &link, &read(&file)?)?;
+        }
+
+        ArticleCommand::Export { link: None, out, hidden } => {
+            if hidden {
+                return Err("Hidden articles are exported along with the others".into());
+            }
+
+            let out = out.ok_or("The directory is missing, pass --out")?;
+
+            println!("{} articles written to {}", content::export(conn, &out)?, out.display());
+        }
+
+        ArticleCommand::Export { link: Some(link), out, hidden } => {
+            let text = articles::text(conn, Table::new(hidden), &link)?;
+
+            match out {
+                Some(out) => fs::write(out, text)?,
+                None => print!("{}", text),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Goes through what the server needs at startup and reports every problem
+// instead of stopping at the first one.
+fn check_config(path: &str) -> Result<(), Box<dyn Error>> {
+    let config = Arc::new(Config::read_from_file(path)
+        .map_err(|e| format!("{} can not be read: {}", path, e))?);
+
+    let mut problems: Vec<String> = Vec::new();
+
+    let mut check = |what: &str, result: Result<(), Box<dyn Error>>| {
+        if let Err(e) = result {
+            problems.push(format!("{}: {}", what, e));
+        }
+    };
+
+    check("token", Auth::new(config.token.clone()).map(|_| ()));
+
+    let catalog = Catalog::load(&config.i18n).map(Arc::new);
+
+    check("locales", catalog.as_ref().map(|_| ()).map_err(|e| e.clone().into()));
+
+    if let Ok(catalog) = &catalog {
+        check("templates", AssetManifest::build(Path::new(STATIC_DIR))
+            .map_err(Box::from)
+            .and_then(|assets| templates::load(&config, &Arc::new(assets), catalog)
+                .map_err(|e| templates::error_chain(&e).into()))
+            .map(|_| ()));
+    }
+
+    // Read only, so a mistyped path is reported instead of becoming an empty
+    // database.
+    check("database", Connection::open_with_flags(&config.database, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0)))
+        .map(|_| ())
+        .map_err(Box::from));
+
+    if config.uses_tls() {
+        check("certificates", Certificates::load(config.clone()).map(|_| ()));
+    }
+
+    if let Some(mail) = &config.mail {
+        check("mail", mail::mailer(mail).map(|_| ()));
+    }
+
+    if let Err(e) = fs::metadata(&config.geoip_db_file) {
+        eprintln!("Warning: {} can not be used, countries are not checked: {}", config.geoip_db_file, e);
+    }
+
+    if problems.is_empty() {
+        println!("{} is fine", path);
+        Ok(())
+    } else {
+        Err(problems.join("\n").into())
+    }
+}
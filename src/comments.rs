@@ -0,0 +1,380 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::collections::HashMap;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use pulldown_cmark::{ html, Options, Parser };
+use rusqlite::{ params, Connection, Row, OptionalExtension };
+use serde::{ Deserialize, Serialize };
+
+use crate::errors::*;
+use crate::i18n;
+use crate::state::State;
+use crate::post::PostDate;
+use crate::spam::{ self, Protection };
+
+const MAX_DEPTH: usize = 5;
+const MAX_AUTHOR_LENGTH: usize = 64;
+const MAX_TEXT_LENGTH: usize = 5000;
+
+#[derive(Serialize)]
+pub struct Comment {
+    pub id: i64,
+    pub article: String,
+    pub parent: Option<i64>,
+    pub author: String,
+    pub html: String,
+    pub date: Option<PostDate>,
+    pub status: String,
+    pub depth: usize,
+}
+
+impl Comment {
+    fn from_row(row: &Row) -> Result<Comment, Box<dyn Error>> {
+        Ok(Comment {
+            id: row.get(0)?,
+            article: row.get(1)?,
+            parent: row.get(2)?,
+            author: row.get(3)?,
+            html: render_markdown(&row.get::<_, String>(4)?),
+            date: PostDate::from_timestamp(row.get(5)?),
+            status: row.get(6)?,
+            depth: 0,
+        })
+    }
+}
+
+pub fn render_markdown(text: &str) -> String {
+    let mut unsafe_html = String::new();
+
+    html::push_html(&mut unsafe_html, Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH));
+
+    ammonia::clean(&unsafe_html)
+}
+
+// Returns approved comments of the article in display order, each reply
+// right after its parent.
+pub fn thread(conn: &Connection, article: &str) -> Result<Vec<Comment>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("
+        SELECT
+            id, article, parent, author, text, date, status
+        FROM
+            comments
+        WHERE
+            article=? AND status='approved'
+        ORDER BY
+            date, id
+    ")?;
+
+    let mut rows = stmt.query(params![article])?;
+    let mut comments: Vec<Comment> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        comments.push(Comment::from_row(row)?);
+    }
+
+    let ids: Vec<i64> = comments.iter().map(|comment| comment.id).collect();
+    let mut children: HashMap<Option<i64>, Vec<Comment>> = HashMap::new();
+
+    for comment in comments {
+        // Replies to comments that are not shown become top level ones.
+        let parent = comment.parent.filter(|parent| ids.contains(parent));
+
+        children.entry(parent).or_default().push(comment);
+    }
+
+    let mut thread = Vec::new();
+
+    flatten(&mut children, None, 0, &mut thread);
+
+    Ok(thread)
+}
+
+fn flatten(children: &mut HashMap<Option<i64>, Vec<Comment>>,
+           parent: Option<i64>,
+           depth: usize,
+           thread: &mut Vec<Comment>) {
+    for mut comment in children.remove(&parent).unwrap_or_default() {
+        let id = comment.id;
+
+        comment.depth = depth.min(MAX_DEPTH);
+        thread.push(comment);
+
+        flatten(children, Some(id), depth + 1, thread);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CommentFormData {
+    author: String,
+    text: String,
+    parent: Option<String>,
+    #[serde(flatten)]
+    protection: Protection,
+}
+
+#[derive(Deserialize)]
+pub struct ModerationQuery {
+    status: Option<String>,
+}
+
+pub async fn comment_submit(req: HttpRequest,
+                            state: web::Data<State<'_>>,
+                            link: web::Path<String>,
+                            form: web::Form<CommentFormData>) -> HttpResponse {
+    try_500!(comment_submit_inner(req, state, link, form).await, state, req)
+}
+
+pub async fn moderation(req: HttpRequest,
+                        state: web::Data<State<'_>>,
+                        query: web::Query<ModerationQuery>) -> HttpResponse {
+    try_500!(moderation_inner(req, state, query).await, state, req)
+}
+
+pub async fn moderate(req: HttpRequest,
+                      state: web::Data<State<'_>>,
+                      path: web::Path<(i64, String)>) -> HttpResponse {
+    try_500!(moderate_inner(req, state, path).await, state, req)
+}
+
+async fn comment_submit_inner(req: HttpRequest,
+                              state: web::Data<State<'_>>,
+                              link: web::Path<String>,
+                              form: web::Form<CommentFormData>) -> Result<HttpResponse, Box<dyn Error>> {
+    let article: Option<String> = state.conn.query_row("
+        SELECT
+            link
+        FROM
+            articles
+        WHERE
+            link=?
+    ", params![link.to_string()], |row| row.get(0)).optional()?;
+
+    let article = match article {
+        Some(article) => article,
+        None => return Ok(error_404(req.clone(), state.clone()).await),
+    };
+
+    let checked = match state.form_guard.check(&form.protection, &[&form.author, &form.text]) {
+        Ok(checked) => checked,
+        Err(rejection) => return spam::rejected(&req, &state, rejection),
+    };
+
+    let author = form.author.trim();
+    let text = form.text.trim();
+
+    let lang = state.lang(&req);
+    let mut errors = Vec::new();
+
+    let parent = match form.parent.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(parent) => match parent.parse::<i64>() {
+            Ok(parent) => Some(parent),
+            Err(_) => {
+                errors.push(state.catalog.translate(&lang, "comment-parent-not-found", None));
+                None
+            }
+        },
+    };
+
+    if author.is_empty() || author.chars().count() > MAX_AUTHOR_LENGTH {
+        errors.push(state.catalog.translate(&lang, "name-length", Some(&i18n::arg("max", MAX_AUTHOR_LENGTH))));
+    }
+
+    if text.is_empty() || text.chars().count() > MAX_TEXT_LENGTH {
+        errors.push(state.catalog.translate(&lang, "comment-length", Some(&i18n::arg("max", MAX_TEXT_LENGTH))));
+    }
+
+    if let Some(parent) = parent {
+        let parent_article: Option<String> = state.conn.query_row("
+            SELECT
+                article
+            FROM
+                comments
+            WHERE
+                id=? AND status='approved'
+        ", params![parent], |row| row.get(0)).optional()?;
+
+        if parent_article.as_deref() != Some(article.as_str()) {
+            errors.push(state.catalog.translate(&lang, "comment-parent-not-found", None));
+        }
+    }
+
+    let mut context = state.context(&req)?;
+
+    context.insert("article", &article);
+    context.insert("errors", &errors);
+
+    if !errors.is_empty() {
+        return Ok(HttpResponse::BadRequest()
+            .body(state.tera.render("comment_sent.html", &context)?));
+    }
+
+    if let Err(rejection) = state.form_guard.consume(checked) {
+        return spam::rejected(&req, &state, rejection);
+    }
+
+    state.conn.execute("
+        INSERT INTO comments (article, parent, author, text, date, status)
+        VALUES (?, ?, ?, ?, strftime('%s', 'now'), 'pending')
+    ", params![article, parent, author, text])?;
+
+    Ok(HttpResponse::Ok().body(state.tera.render("comment_sent.html", &context)?))
+}
+
+async fn moderation_inner(req: HttpRequest,
+                          state: web::Data<State<'_>>,
+                          query: web::Query<ModerationQuery>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    let status = query.status.as_deref().unwrap_or("pending");
+
+    if !["pending", "approved", "rejected", "spam"].contains(&status) {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    let mut context = state.context(&req)?;
+
+    let mut stmt = state.conn.prepare("
+        SELECT
+            id, article, parent, author, text, date, status
+        FROM
+            comments
+        WHERE
+            status=?
+        ORDER BY
+            date DESC
+    ")?;
+
+    let mut rows = stmt.query(params![status])?;
+    let mut comments: Vec<Comment> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        comments.push(Comment::from_row(row)?);
+    }
+
+    context.insert("comments", &comments);
+    context.insert("status", status);
+
+    Ok(HttpResponse::Ok().body(state.tera.render("comments.html", &context)?))
+}
+
+async fn moderate_inner(req: HttpRequest,
+                        state: web::Data<State<'_>>,
+                        path: web::Path<(i64, String)>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    let (id, action) = path.into_inner();
+
+    let status = match action.as_str() {
+        "approve" => "approved",
+        "reject" => "rejected",
+        "spam" => "spam",
+        _ => return Ok(error_404(req.clone(), state.clone()).await),
+    };
+
+    state.conn.execute("UPDATE comments SET status=? WHERE id=?", params![status, id])?;
+    state.page_cache.invalidate();
+
+    Ok(HttpResponse::SeeOther()
+        .header("Location", "/comments")
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{ params, Connection };
+
+    use crate::schema;
+    use super::{ render_markdown, thread, MAX_DEPTH };
+
+    fn database() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        schema::migrate(&mut conn).unwrap();
+
+        conn
+    }
+
+    fn comment(conn: &Connection, id: i64, article: &str, parent: Option<i64>, date: i64, status: &str) {
+        conn.execute("
+            INSERT INTO comments (id, article, parent, author, text, date, status)
+            VALUES (?, ?, ?, 'Reader', 'Text', ?, ?)
+        ", params![id, article, parent, date, status]).unwrap();
+    }
+
+    fn order(conn: &Connection, article: &str) -> Vec<(i64, usize)> {
+        thread(conn, article).unwrap().iter().map(|comment| (comment.id, comment.depth)).collect()
+    }
+
+    #[test]
+    fn replies_after_parents() {
+        let conn = database();
+
+        comment(&conn, 1, "first", None, 10, "approved");
+        comment(&conn, 2, "first", None, 20, "approved");
+        comment(&conn, 3, "first", Some(1), 30, "approved");
+        comment(&conn, 4, "first", Some(3), 40, "approved");
+        comment(&conn, 5, "first", Some(1), 50, "approved");
+        comment(&conn, 6, "second", None, 15, "approved");
+
+        assert_eq!(order(&conn, "first"), vec![(1, 0), (3, 1), (4, 2), (5, 1), (2, 0)]);
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        let conn = database();
+
+        comment(&conn, 1, "first", None, 1, "approved");
+
+        for id in 2..=MAX_DEPTH as i64 + 3 {
+            comment(&conn, id, "first", Some(id - 1), id, "approved");
+        }
+
+        let depths: Vec<usize> = order(&conn, "first").iter().map(|&(_, depth)| depth).collect();
+
+        assert_eq!(depths, vec![0, 1, 2, 3, 4, 5, 5, 5]);
+    }
+
+    #[test]
+    fn orphans_become_top_level() {
+        let conn = database();
+
+        comment(&conn, 1, "first", None, 10, "pending");
+        comment(&conn, 2, "first", Some(1), 20, "approved");
+        comment(&conn, 3, "first", Some(2), 30, "approved");
+        comment(&conn, 4, "first", Some(99), 40, "approved");
+        comment(&conn, 5, "first", None, 50, "spam");
+
+        assert_eq!(order(&conn, "first"), vec![(2, 0), (3, 1), (4, 0)]);
+    }
+
+    #[test]
+    fn markdown_is_sanitized() {
+        assert_eq!(render_markdown("**bold** ~~gone~~"), "<p><strong>bold</strong> <del>gone</del></p>\n");
+        assert_eq!(render_markdown("<script>alert(1)</script>"), "");
+        assert_eq!(render_markdown("text <img src=x onerror=alert(1)>"), "<p>text <img src=\"x\"></p>\n");
+        assert_eq!(render_markdown("[link](javascript:alert(1))"), "<p><a rel=\"noopener noreferrer\">link</a></p>\n");
+        assert_eq!(render_markdown("[link](https://example.org)"),
+                   "<p><a href=\"https://example.org\" rel=\"noopener noreferrer\">link</a></p>\n");
+    }
+}
@@ -0,0 +1,488 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::task::{ Context, Poll };
+use std::time::SystemTime;
+use actix_files::NamedFile;
+use actix_web::{ web, Error, HttpRequest, HttpResponse };
+use actix_web::body::{ Body, ResponseBody };
+use actix_web::dev::{ Service, ServiceRequest, ServiceResponse, Transform };
+use actix_web::http::{ HeaderMap, HeaderValue, header };
+use futures::future::{ ok, FutureExt, LocalBoxFuture, Ready };
+
+use crate::state::State;
+use crate::assets::STATIC_DIR;
+use crate::errors::*;
+
+const MIN_SIZE: usize = 256;
+
+const PRECOMPRESSED_EXTENSIONS: &[&str] = &[
+    "css", "html", "ico", "js", "json", "svg", "txt", "xml",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    // In order of preference when the client weighs them equally.
+    const ALL: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zst",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    pub fn compress(self, data: &[u8], best: bool) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Zstd => zstd::encode_all(data, if best { 19 } else { 3 }),
+
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                let quality = if best { 11 } else { 5 };
+
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut output, 4096, quality, 22);
+                    writer.write_all(data)?;
+                }
+
+                Ok(output)
+            }
+
+            Encoding::Gzip => {
+                let level = if best {
+                    flate2::Compression::best()
+                } else {
+                    flate2::Compression::default()
+                };
+
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// Returns the supported encodings acceptable to the client, best first.
+pub fn negotiate(headers: &HeaderMap) -> Vec<Encoding> {
+    let mut accepted: Vec<(Encoding, f32)> = Vec::new();
+
+    let values = headers.get_all(header::ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+
+    for value in values {
+        let mut parts = value.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+
+        let quality = parts
+            .filter_map(|part| part.strip_prefix("q="))
+            .find_map(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        for encoding in Encoding::ALL.iter() {
+            if name == encoding.name() || name == "*" {
+                if let Some(known) = accepted.iter_mut().find(|(known, _)| known == encoding) {
+                    // An explicit entry overrides the wildcard.
+                    if name != "*" {
+                        known.1 = quality;
+                    }
+                } else {
+                    accepted.push((*encoding, quality));
+                }
+            }
+        }
+    }
+
+    accepted.retain(|(_, quality)| *quality > 0.0);
+
+    let preference = |encoding: &Encoding| {
+        Encoding::ALL.iter().position(|known| known == encoding).unwrap_or_default()
+    };
+
+    accepted.sort_by(|(a, a_quality), (b, b_quality)| {
+        b_quality.partial_cmp(a_quality)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(preference(a).cmp(&preference(b)))
+    });
+
+    accepted.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+pub struct Compress;
+
+impl<S> Transform<S> for Compress
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CompressMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CompressMiddleware { service })
+    }
+}
+
+pub struct CompressMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for CompressMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let encoding = negotiate(req.headers()).into_iter().next();
+        let fut = self.service.call(req);
+
+        async move {
+            let res = fut.await?;
+
+            let encoding = match encoding {
+                Some(encoding) => encoding,
+                None => return Ok(res),
+            };
+
+            Ok(res.map_body(|head, body| {
+                let headers = &mut head.headers;
+
+                if headers.contains_key(header::CONTENT_ENCODING) || !compressible(headers) {
+                    return body;
+                }
+
+                let data = match &body {
+                    ResponseBody::Body(Body::Bytes(data)) if data.len() >= MIN_SIZE => data,
+                    _ => return body,
+                };
+
+                let compressed = match encoding.compress(data, false) {
+                    Ok(compressed) => compressed,
+                    Err(e) => {
+                        eprintln!("Response compression failed: {}", e);
+                        return body;
+                    }
+                };
+
+                headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+                headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+                weaken_etag(headers);
+
+                ResponseBody::Body(Body::from(compressed))
+            }))
+        }
+        .boxed_local()
+    }
+}
+
+fn compressible(headers: &HeaderMap) -> bool {
+    let content_type = match headers.get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type.to_str().unwrap_or_default(),
+        // Pages are rendered without an explicit content type.
+        None => return true,
+    };
+
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    mime == "text/html" || mime == "text/xml" || mime == "application/xml"
+        || mime.ends_with("+xml")
+}
+
+// A compressed body is a different representation, so a strong validator
+// would be wrong; a weak one still matches on If-None-Match.
+fn weaken_etag(headers: &mut HeaderMap) {
+    let etag = match headers.get(header::ETAG).and_then(|etag| etag.to_str().ok()) {
+        Some(etag) if !etag.starts_with("W/") => format!("W/{}", etag),
+        _ => return,
+    };
+
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(header::ETAG, etag);
+    }
+}
+
+pub async fn static_file(req: HttpRequest,
+                         state: web::Data<State<'_>>,
+                         path: web::Path<String>) -> HttpResponse {
+    match serve_static(&req, Path::new(STATIC_DIR), &path) {
+        Some(response) => try_500!(response, state, req),
+        None => error_404(req, state).await,
+    }
+}
+
+pub fn serve_static(req: &HttpRequest,
+                    root: &Path,
+                    path: &str) -> Option<Result<HttpResponse, Error>> {
+    let file = resolve(root, path)?;
+
+    if !file.is_file() {
+        return None;
+    }
+
+    let content_type = mime_guess::from_path(&file).first_or_octet_stream();
+    let modified = fs::metadata(&file).and_then(|metadata| metadata.modified()).ok()?;
+
+    for encoding in negotiate(req.headers()) {
+        let mut sibling = file.clone().into_os_string();
+        sibling.push(".");
+        sibling.push(encoding.extension());
+
+        // A copy older than its file is left from before an edit.
+        if !up_to_date(Path::new(&sibling), modified) {
+            continue;
+        }
+
+        if let Ok(named) = NamedFile::open(&sibling) {
+            let response = named.set_content_type(content_type)
+                .disable_content_disposition()
+                .into_response(req)
+                .map(|mut response| {
+                    let headers = response.headers_mut();
+
+                    headers.insert(header::CONTENT_ENCODING,
+                                   HeaderValue::from_static(encoding.name()));
+                    headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+
+                    response
+                });
+
+            return Some(response);
+        }
+    }
+
+    let named = NamedFile::open(&file).ok()?;
+
+    Some(named.set_content_type(content_type)
+        .into_response(req)
+        .map(|mut response| {
+            if PRECOMPRESSED_EXTENSIONS.iter().any(|ext| has_extension(&file, ext)) {
+                response.headers_mut()
+                    .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+            }
+
+            response
+        }))
+}
+
+pub fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = root.to_path_buf();
+
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        if segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+
+        file.push(segment);
+    }
+
+    Some(file)
+}
+
+pub fn precompress_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            precompress_dir(&path)?;
+        } else if PRECOMPRESSED_EXTENSIONS.iter().any(|ext| has_extension(&path, ext)) {
+            precompress_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn precompress_file(path: &Path) -> io::Result<()> {
+    let modified = fs::metadata(path)?.modified()?;
+    let mut data = None;
+
+    for encoding in Encoding::ALL.iter() {
+        let mut sibling = path.to_path_buf().into_os_string();
+        sibling.push(".");
+        sibling.push(encoding.extension());
+        let sibling = PathBuf::from(sibling);
+
+        if up_to_date(&sibling, modified) {
+            continue;
+        }
+
+        if data.is_none() {
+            data = Some(fs::read(path)?);
+        }
+
+        let data = data.as_deref().unwrap_or_default();
+        let compressed = encoding.compress(data, true)?;
+
+        // Not worth a sibling if it barely saves anything.
+        if compressed.len() + compressed.len() / 10 < data.len() {
+            fs::write(&sibling, compressed)?;
+            println!("{}", sibling.display());
+        } else if sibling.exists() {
+            fs::remove_file(&sibling)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn up_to_date(sibling: &Path, modified: SystemTime) -> bool {
+    fs::metadata(sibling)
+        .and_then(|metadata| metadata.modified())
+        .map(|sibling_modified| sibling_modified >= modified)
+        .unwrap_or(false)
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .and_then(|path_extension| path_extension.to_str())
+        .map(|path_extension| path_extension.eq_ignore_ascii_case(extension))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{ self, File };
+    use std::path::{ Path, PathBuf };
+    use std::time::{ Duration, SystemTime };
+    use actix_web::http::{ HeaderMap, HeaderValue, header };
+    use actix_web::test::TestRequest;
+
+    use super::{ negotiate, resolve, serve_static, Encoding };
+
+    fn accept(value: &'static str) -> Vec<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+
+        negotiate(&headers)
+    }
+
+    #[test]
+    fn quality_order() {
+        assert_eq!(accept("gzip;q=1.0, br;q=0.5, zstd;q=0.8"),
+                   vec![Encoding::Gzip, Encoding::Zstd, Encoding::Brotli]);
+    }
+
+    #[test]
+    fn equal_quality_uses_preference() {
+        assert_eq!(accept("gzip, br, zstd"),
+                   vec![Encoding::Zstd, Encoding::Brotli, Encoding::Gzip]);
+        assert_eq!(accept("GZIP, Br"), vec![Encoding::Brotli, Encoding::Gzip]);
+    }
+
+    #[test]
+    fn zero_quality_is_refused() {
+        assert_eq!(accept("gzip, br;q=0"), vec![Encoding::Gzip]);
+        assert_eq!(accept("gzip;q=0.0"), vec![]);
+    }
+
+    #[test]
+    fn identity_is_not_an_encoding() {
+        assert_eq!(accept("identity;q=0"), vec![]);
+        assert_eq!(accept("gzip, identity;q=0"), vec![Encoding::Gzip]);
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(accept("*;q=0.5, gzip"),
+                   vec![Encoding::Gzip, Encoding::Zstd, Encoding::Brotli]);
+        assert_eq!(accept("*, br;q=0"), vec![Encoding::Zstd, Encoding::Gzip]);
+        assert_eq!(accept("br;q=0, *"), vec![Encoding::Zstd, Encoding::Gzip]);
+    }
+
+    #[test]
+    fn without_header() {
+        assert_eq!(negotiate(&HeaderMap::new()), vec![]);
+    }
+
+    #[test]
+    fn resolve_inside_root() {
+        let root = Path::new("static");
+
+        assert_eq!(resolve(root, "/styles/style.css"), Some(PathBuf::from("static/styles/style.css")));
+        assert_eq!(resolve(root, "//images//a.png"), Some(PathBuf::from("static/images/a.png")));
+    }
+
+    #[test]
+    fn resolve_rejects_traversal() {
+        let root = Path::new("static");
+
+        assert_eq!(resolve(root, "/../config.json"), None);
+        assert_eq!(resolve(root, "/styles/../../config.json"), None);
+        assert_eq!(resolve(root, "/.git/config"), None);
+        assert_eq!(resolve(root, "/styles\\..\\..\\config.json"), None);
+    }
+
+    fn served_encoding(root: &Path) -> Option<String> {
+        let req = TestRequest::default().header(header::ACCEPT_ENCODING, "gzip").to_http_request();
+        let response = serve_static(&req, root, "/style.css").unwrap().unwrap();
+
+        response.headers().get(header::CONTENT_ENCODING).map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn stale_sibling_is_skipped() {
+        let root = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+
+        fs::write(root.path().join("style.css"), "body {}").unwrap();
+        fs::write(root.path().join("style.css.gz"), "old").unwrap();
+
+        File::options().write(true).open(root.path().join("style.css.gz")).unwrap()
+            .set_modified(now).unwrap();
+        File::options().write(true).open(root.path().join("style.css")).unwrap()
+            .set_modified(now - Duration::from_secs(60)).unwrap();
+
+        assert_eq!(served_encoding(root.path()).as_deref(), Some("gzip"));
+
+        File::options().write(true).open(root.path().join("style.css")).unwrap()
+            .set_modified(now + Duration::from_secs(60)).unwrap();
+
+        assert_eq!(served_encoding(root.path()), None);
+    }
+}
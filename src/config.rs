@@ -17,17 +17,266 @@
 
 use std::{ fs, io::BufReader, error::Error };
 use serde::Deserialize;
+use chrono_tz::Tz;
 use serde_json::from_reader;
 
 #[derive(Deserialize)]
 pub struct Config {
-    pub priv_key_file: String,
-    pub cert_chain_file: String,
+    #[serde(default)]
+    pub priv_key_file: Option<String>,
+    #[serde(default)]
+    pub cert_chain_file: Option<String>,
     pub host: String,
     pub database: String,
     pub templates: String,
     pub token: String,
-    pub geoip_db_file: String
+    pub geoip_db_file: String,
+    #[serde(default)]
+    pub certificates: Vec<Certificate>,
+    #[serde(default)]
+    pub listen: Option<Vec<Listener>>,
+    #[serde(default)]
+    pub behind_proxy: bool,
+    #[serde(default)]
+    pub allow_private_addresses: bool,
+    #[serde(default)]
+    pub development: bool,
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    #[serde(default = "default_cache_max_age")]
+    pub cache_max_age: u64,
+    #[serde(default = "default_page_cache_size")]
+    pub page_cache_size: usize,
+    #[serde(default)]
+    pub images: Images,
+    #[serde(default)]
+    pub media: Media,
+    #[serde(default)]
+    pub spam: Spam,
+    #[serde(default)]
+    pub mail: Option<Mail>,
+    #[serde(default)]
+    pub activitypub: Option<ActivityPub>,
+    #[serde(default)]
+    pub content: Option<Content>,
+    #[serde(default)]
+    pub i18n: I18n,
+    #[serde(default)]
+    pub dates: Dates,
+}
+
+#[derive(Deserialize)]
+pub struct Certificate {
+    pub hosts: Vec<String>,
+    pub priv_key_file: String,
+    pub cert_chain_file: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Listener {
+    Https { address: String },
+    Http { address: String },
+    Unix { path: String },
+    Redirect { address: String },
+    Gemini { address: String },
+    Gopher { address: String },
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    pub hsts_preload: bool,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub content_security_policy: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders {
+            hsts_max_age: 31536000,
+            hsts_include_subdomains: false,
+            hsts_preload: false,
+            referrer_policy: "strict-origin-when-cross-origin".to_owned(),
+            permissions_policy: "camera=(), geolocation=(), microphone=()".to_owned(),
+            content_security_policy: "default-src 'self'; \
+                script-src 'self' 'nonce-{nonce}'; \
+                style-src 'self' 'nonce-{nonce}'; \
+                img-src 'self' data:; \
+                object-src 'none'; \
+                base-uri 'self'; \
+                form-action 'self'; \
+                frame-ancestors 'none'".to_owned(),
+        }
+    }
+}
+
+fn default_cache_max_age() -> u64 {
+    300
+}
+
+fn default_page_cache_size() -> usize {
+    128
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Images {
+    pub cache_dir: String,
+    pub widths: Vec<u32>,
+}
+
+impl Default for Images {
+    fn default() -> Images {
+        Images {
+            cache_dir: "image_cache".to_owned(),
+            widths: vec![320, 640, 960, 1280, 1920],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Media {
+    pub dir: String,
+    pub max_size: usize,
+}
+
+impl Default for Media {
+    fn default() -> Media {
+        Media {
+            dir: "media".to_owned(),
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Spam {
+    pub min_submit_time: u64,
+    pub max_token_age: u64,
+    pub pow_difficulty: u32,
+    pub max_links: usize,
+    pub blocked_words: Vec<String>,
+}
+
+impl Default for Spam {
+    fn default() -> Spam {
+        Spam {
+            min_submit_time: 3,
+            max_token_age: 24 * 60 * 60,
+            pow_difficulty: 16,
+            max_links: 3,
+            blocked_words: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Mail {
+    #[serde(default)]
+    pub transport: MailTransport,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default = "default_mail_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: MailTls,
+    #[serde(default = "default_mail_dir")]
+    pub dir: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    #[default]
+    Smtp,
+    File,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTls {
+    None,
+    #[default]
+    Starttls,
+    Tls,
+}
+
+fn default_mail_port() -> u16 {
+    587
+}
+
+fn default_mail_dir() -> String {
+    "mail".to_owned()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ActivityPub {
+    pub username: String,
+    pub name: String,
+    #[serde(default)]
+    pub summary: String,
+    #[serde(default = "default_activitypub_key_file")]
+    pub key_file: String,
+}
+
+fn default_activitypub_key_file() -> String {
+    "activitypub.pem".to_owned()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Content {
+    pub dir: String,
+    #[serde(default = "default_content_poll_interval")]
+    pub poll_interval: u64,
+}
+
+fn default_content_poll_interval() -> u64 {
+    2
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct I18n {
+    pub dir: String,
+    pub default_locale: String,
+}
+
+impl Default for I18n {
+    fn default() -> I18n {
+        I18n {
+            dir: "locales".to_owned(),
+            default_locale: "ru".to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Dates {
+    pub timezone: Tz,
+    // Readers get dates in the time zone their browser tells in a cookie.
+    pub reader_timezone: bool,
+}
+
+impl Default for Dates {
+    fn default() -> Dates {
+        Dates {
+            timezone: Tz::UTC,
+            reader_timezone: false,
+        }
+    }
 }
 
 impl Config {
@@ -37,4 +286,27 @@ impl Config {
 
         Ok(config)
     }
+
+    pub fn hosts(&self) -> impl Iterator<Item = &String> {
+        let other_hosts = self.certificates.iter()
+            .flat_map(|certificate| certificate.hosts.iter())
+            .filter(|host| !host.starts_with("*."));
+
+        std::iter::once(&self.host).chain(other_hosts)
+    }
+
+    pub fn listeners(&self) -> Vec<Listener> {
+        match &self.listen {
+            Some(listen) => listen.clone(),
+            None => vec![
+                Listener::Redirect { address: format!("{}:80", self.host) },
+                Listener::Https { address: format!("{}:443", self.host) },
+            ],
+        }
+    }
+
+    pub fn uses_tls(&self) -> bool {
+        self.listeners().iter()
+            .any(|listener| matches!(listener, Listener::Https { .. } | Listener::Gemini { .. }))
+    }
 }
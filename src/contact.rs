@@ -0,0 +1,319 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::error::BlockingError;
+use lettre::Address;
+use lettre::address::AddressError;
+use lettre::message::Mailbox;
+use rusqlite::{ params, Connection, Row };
+use serde::{ Deserialize, Serialize };
+
+use crate::config::Mail;
+use crate::errors::*;
+use crate::i18n;
+use crate::state::State;
+use crate::post::PostDate;
+use crate::mail::Letter;
+use crate::spam::{ self, Protection };
+
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_TEXT_LENGTH: usize = 5000;
+
+#[derive(Serialize)]
+pub struct ContactMessage {
+    pub id: i64,
+    pub name: String,
+    pub email: String,
+    pub text: String,
+    pub date: Option<PostDate>,
+    pub error: Option<String>,
+}
+
+impl ContactMessage {
+    fn from_row(row: &Row) -> Result<ContactMessage, Box<dyn Error>> {
+        Ok(ContactMessage {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            email: row.get(2)?,
+            text: row.get(3)?,
+            date: PostDate::from_timestamp(row.get(4)?),
+            error: row.get(5)?,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ContactFormData {
+    name: String,
+    email: String,
+    text: String,
+    #[serde(flatten, skip_serializing)]
+    protection: Protection,
+}
+
+pub async fn contact(req: HttpRequest,
+                     state: web::Data<State<'_>>) -> HttpResponse {
+    let context = try_500!(state.context(&req), state, req);
+
+    HttpResponse::Ok().body(try_500!(state.tera.render("contact.html", &context), state, req))
+}
+
+pub async fn contact_submit(req: HttpRequest,
+                            state: web::Data<State<'_>>,
+                            form: web::Form<ContactFormData>) -> HttpResponse {
+    try_500!(contact_submit_inner(req, state, form).await, state, req)
+}
+
+pub async fn messages(req: HttpRequest,
+                      state: web::Data<State<'_>>) -> HttpResponse {
+    try_500!(messages_inner(req, state).await, state, req)
+}
+
+pub async fn message_delete(req: HttpRequest,
+                            state: web::Data<State<'_>>,
+                            id: web::Path<i64>) -> HttpResponse {
+    try_500!(message_delete_inner(req, state, id).await, state, req)
+}
+
+async fn contact_submit_inner(req: HttpRequest,
+                              state: web::Data<State<'_>>,
+                              form: web::Form<ContactFormData>) -> Result<HttpResponse, Box<dyn Error>> {
+    let checked = match state.form_guard.check(&form.protection, &[&form.name, &form.text]) {
+        Ok(checked) => checked,
+        Err(rejection) => return spam::rejected(&req, &state, rejection),
+    };
+
+    let name = form.name.trim();
+    let email = form.email.trim();
+    let text = form.text.trim();
+
+    let lang = state.lang(&req);
+    let mut errors = Vec::new();
+
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        errors.push(state.catalog.translate(&lang, "name-length", Some(&i18n::arg("max", MAX_NAME_LENGTH))));
+    }
+
+    let address = email.parse::<Address>();
+
+    if address.is_err() {
+        errors.push(state.catalog.translate(&lang, "email-invalid", None));
+    }
+
+    if text.is_empty() || text.chars().count() > MAX_TEXT_LENGTH {
+        errors.push(state.catalog.translate(&lang, "message-length", Some(&i18n::arg("max", MAX_TEXT_LENGTH))));
+    }
+
+    let mut context = state.context(&req)?;
+
+    if !errors.is_empty() {
+        context.insert("errors", &errors);
+        context.insert("form", &*form);
+
+        return Ok(HttpResponse::BadRequest().body(state.tera.render("contact.html", &context)?));
+    }
+
+    if let Err(rejection) = state.form_guard.consume(checked) {
+        return spam::rejected(&req, &state, rejection);
+    }
+
+    let subject = state.catalog.translate(state.catalog.default_locale(),
+                                          "contact-mail-subject",
+                                          Some(&i18n::arg("name", name)));
+
+    // Whatever could not be mailed is kept, so no message is ever lost.
+    let error = match (state.mailer.clone(), letter(state.config.mail.as_ref(), name, address, subject, text)) {
+        (Some(mailer), Ok(letter)) => {
+            match web::block(move || mailer.send(letter).map_err(|e| e.to_string())).await {
+                Ok(()) => None,
+                Err(BlockingError::Error(e)) => Some(e),
+                Err(BlockingError::Canceled) => Some("Mail sending was canceled".to_owned()),
+            }
+        }
+
+        (None, _) => Some("Mail is not configured".to_owned()),
+        (_, Err(e)) => Some(e),
+    };
+
+    if let Some(error) = &error {
+        eprintln!("Contact message delivery failed, storing it: {}", error);
+        store(&state.conn, name, email, text, error)?;
+    }
+
+    context.insert("sent", &true);
+
+    Ok(HttpResponse::Ok().body(state.tera.render("contact.html", &context)?))
+}
+
+// The letter to the author of the site, answered straight to the sender.
+fn letter(config: Option<&Mail>,
+          name: &str,
+          address: Result<Address, AddressError>,
+          subject: String,
+          text: &str) -> Result<Letter, String> {
+    let config = config.ok_or("Mail is not configured")?;
+
+    let to = config.to.parse::<Mailbox>()
+        .map_err(|e| format!("Invalid mail.to address {}: {}", config.to, e))?;
+
+    let address = address.map_err(|e| e.to_string())?;
+
+    Ok(Letter {
+        to,
+        reply_to: Some(Mailbox::new(Some(name.to_owned()), address)),
+        subject,
+        body: text.to_owned(),
+        unsubscribe: None,
+    })
+}
+
+fn store(conn: &Connection, name: &str, email: &str, text: &str, error: &str) -> rusqlite::Result<()> {
+    conn.execute("
+        INSERT INTO messages (name, email, text, date, error)
+        VALUES (?, ?, ?, strftime('%s', 'now'), ?)
+    ", params![name, email, text, error])?;
+
+    Ok(())
+}
+
+async fn messages_inner(req: HttpRequest,
+                        state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    let mut context = state.context(&req)?;
+
+    let mut stmt = state.conn.prepare("
+        SELECT
+            id, name, email, text, date, error
+        FROM
+            messages
+        ORDER BY
+            date DESC
+    ")?;
+
+    let mut rows = stmt.query([])?;
+    let mut messages: Vec<ContactMessage> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        messages.push(ContactMessage::from_row(row)?);
+    }
+
+    context.insert("messages", &messages);
+
+    Ok(HttpResponse::Ok().body(state.tera.render("messages.html", &context)?))
+}
+
+async fn message_delete_inner(req: HttpRequest,
+                              state: web::Data<State<'_>>,
+                              id: web::Path<i64>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    state.conn.execute("DELETE FROM messages WHERE id=?", params![*id])?;
+
+    Ok(HttpResponse::SeeOther()
+        .header("Location", "/messages")
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use lettre::Address;
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    use crate::config::Mail;
+    use crate::{ mail, schema };
+    use super::{ letter, store };
+
+    fn mail_config(dir: &Path, to: &str) -> Mail {
+        serde_json::from_value(json!({
+            "transport": "file",
+            "dir": dir,
+            "from": "Blog <blog@example.org>",
+            "to": to,
+        })).unwrap()
+    }
+
+    #[test]
+    fn mailed_through_file_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = mail_config(dir.path(), "me@example.org");
+
+        let letter = letter(Some(&config), "Reader", "reader@example.net".parse::<Address>(),
+                            "Message from Reader".to_owned(), "Hello there").unwrap();
+
+        mail::mailer(&config).unwrap().send(letter).unwrap();
+
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+
+        assert_eq!(files.len(), 1);
+
+        let message = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        let header = |name: &str| message.lines()
+            .find(|line| line.starts_with(&format!("{}: ", name)))
+            .map(str::to_owned);
+
+        assert_eq!(header("To").as_deref(), Some("To: me@example.org"));
+        assert!(header("Reply-To").unwrap().contains("<reader@example.net>"));
+        assert_eq!(header("Subject").as_deref(), Some("Subject: Message from Reader"));
+        assert!(message.trim_end().ends_with("Hello there"));
+    }
+
+    #[test]
+    fn undeliverable_message_is_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        schema::migrate(&mut conn).unwrap();
+
+        let address = || "reader@example.net".parse::<Address>();
+
+        let errors = vec![
+            letter(None, "Reader", address(), String::new(), "Hello").err().unwrap(),
+            letter(Some(&mail_config(dir.path(), "not an address")), "Reader", address(), String::new(), "Hello")
+                .err().unwrap(),
+            letter(Some(&mail_config(dir.path(), "me@example.org")), "Reader", "nobody".parse(), String::new(), "Hello")
+                .err().unwrap(),
+        ];
+
+        assert_eq!(errors[0], "Mail is not configured");
+        assert!(errors[1].starts_with("Invalid mail.to address not an address"));
+
+        for error in &errors {
+            store(&conn, "Reader", "reader@example.net", "Hello", error).unwrap();
+        }
+
+        let stored: Vec<(String, String, String)> = conn.prepare("SELECT name, text, error FROM messages ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored[0], ("Reader".to_owned(), "Hello".to_owned(), "Mail is not configured".to_owned()));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,637 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io;
+use std::thread;
+use std::error::Error;
+use std::collections::HashSet;
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, SystemTime };
+use chrono::{ DateTime, NaiveDate, NaiveDateTime, SecondsFormat };
+use rusqlite::{ params, Connection, OptionalExtension };
+use serde::{ Deserialize, Serialize };
+
+use crate::articles::{ self, Table };
+use crate::config::Content;
+use crate::markup;
+
+// Articles kept as files, for authors who prefer writing in git. A file
+// starts with front matter between `---` lines in YAML or `+++` lines in
+// TOML. The body of `.md` files is markdown, of `.html` files it is taken
+// as is. Exported files have every column, so they can be imported back.
+#[derive(Deserialize, Serialize)]
+pub struct FrontMatter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lastmod: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    // Translations of an article share this, it is the link when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short_text: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub hidden: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dnshow: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+pub struct Document {
+    pub front_matter: FrontMatter,
+    pub body: String,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub deleted: Vec<String>,
+    // Files that could not be imported when going on past them.
+    pub failed: Vec<String>,
+}
+
+// Files without front matter give None.
+pub fn parse(source: &str) -> Result<Option<Document>, Box<dyn Error>> {
+    let source = source.trim_start_matches('\u{feff}');
+
+    let delimiter = match source.lines().next().map(str::trim_end) {
+        Some(delimiter @ ("---" | "+++")) => delimiter,
+        _ => return Ok(None),
+    };
+
+    let rest = &source[source.find('\n').map(|end| end + 1).unwrap_or(source.len())..];
+    let mut offset = 0;
+
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == delimiter {
+            let front = &rest[..offset];
+            let body = rest[offset + line.len()..].to_owned();
+
+            let front_matter = if delimiter == "---" {
+                serde_yaml::from_str(front)?
+            } else {
+                toml_front_matter(front)?
+            };
+
+            return Ok(Some(Document { front_matter, body }));
+        }
+
+        offset += line.len();
+    }
+
+    Err(format!("Front matter is not closed with {}", delimiter).into())
+}
+
+// TOML has dates of its own, they are read like the strings YAML gives.
+fn toml_front_matter(front: &str) -> Result<FrontMatter, Box<dyn Error>> {
+    let mut table: toml::Table = toml::from_str(front)?;
+
+    for (_, value) in table.iter_mut() {
+        if let toml::Value::Datetime(datetime) = value {
+            *value = toml::Value::String(datetime.to_string());
+        }
+    }
+
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+// Dates without a time zone are in UTC, like everywhere on the site.
+pub fn parse_date(date: &str) -> Result<i64, Box<dyn Error>> {
+    let date = date.trim();
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date.timestamp());
+    }
+
+    for format in &["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(date, format) {
+            return Ok(date.and_utc().timestamp());
+        }
+    }
+
+    match NaiveDate::parse_from_str(date, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)) {
+        Some(date) => Ok(date.and_utc().timestamp()),
+        None => Err(format!("Date {} is not understood", date).into()),
+    }
+}
+
+fn is_article_file(path: &Path) -> bool {
+    matches!(path.extension().and_then(|extension| extension.to_str()), Some("md" | "html"))
+}
+
+// Imports a file or every file of a directory in one transaction. With
+// `prune`, articles imported from the directory before whose files are gone
+// are deleted. With `keep_going`, a broken file is reported and its article
+// is left as it was, instead of failing the whole import.
+pub fn import(conn: &mut Connection,
+              path: &Path,
+              prune: bool,
+              keep_going: bool) -> Result<Report, Box<dyn Error>> {
+    let path = path.canonicalize()?;
+    let mut transaction = conn.transaction()?;
+    let mut report = Report::default();
+    let mut seen = HashSet::new();
+    let mut failed = HashSet::new();
+
+    if path.is_dir() {
+        let mut files: Vec<_> = fs::read_dir(&path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+
+        files.retain(|file| file.is_file() && is_article_file(file));
+        files.sort();
+
+        for file in files {
+            // A file failing halfway leaves nothing of itself behind.
+            let savepoint = transaction.savepoint()?;
+
+            match import_file(&savepoint, &file, &mut report, &mut seen) {
+                Ok(()) => savepoint.commit()?,
+                Err(e) if keep_going => {
+                    report.failed.push(e.to_string());
+                    failed.insert(file.to_string_lossy().into_owned());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    } else if prune {
+        return Err("Only a directory can be pruned".into());
+    } else {
+        import_file(&transaction, &path, &mut report, &mut seen)?;
+    }
+
+    if prune {
+        let mut stmt = transaction.prepare("SELECT link, hidden, file FROM imported")?;
+
+        let imported: Vec<(String, bool, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+
+        drop(stmt);
+
+        for (link, hidden, file) in imported {
+            let gone = Path::new(&file).parent() == Some(path.as_path())
+                && !seen.contains(&(link.clone(), hidden))
+                && !failed.contains(&file);
+
+            if gone {
+                articles::delete(&transaction, Table::new(hidden), &link)?;
+                report.deleted.push(link);
+            }
+        }
+    }
+
+    transaction.commit()?;
+
+    Ok(report)
+}
+
+fn import_file(conn: &Connection,
+               file: &Path,
+               report: &mut Report,
+               seen: &mut HashSet<(String, bool)>) -> Result<(), Box<dyn Error>> {
+    let failed = |e: Box<dyn Error>| -> Box<dyn Error> { format!("{}: {}", file.display(), e).into() };
+
+    let document = parse(&fs::read_to_string(file)?)
+        .map_err(failed)?
+        .ok_or_else(|| failed("There is no front matter".into()))?;
+
+    let front_matter = document.front_matter;
+
+    let link = match front_matter.link.clone() {
+        Some(link) => link,
+        None => file.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+    };
+
+    if link.is_empty() || link.contains('/') {
+        return Err(failed(format!("Link {:?} can not be used", link).into()));
+    }
+
+    let markdown = file.extension().and_then(|extension| extension.to_str()) == Some("md");
+    let convert = |text: &str| if markdown { markup::markdown(text) } else { text.to_owned() };
+
+    let table = Table::new(front_matter.hidden);
+    let text = convert(&document.body);
+    let short_text = front_matter.short_text.as_deref().map(convert);
+    let date = front_matter.date.as_deref().map(parse_date).transpose().map_err(failed)?;
+    let lastmod = front_matter.lastmod.as_deref().map(parse_date).transpose().map_err(failed)?;
+
+    if table.hidden() && (short_text.is_some() || front_matter.dnshow) {
+        return Err(failed("Hidden articles have neither short text nor listing".into()));
+    }
+
+    let translation = match (&front_matter.lang, &front_matter.translation) {
+        (Some(lang), key) => Some((lang.clone(), key.clone().unwrap_or_else(|| link.clone()))),
+        (None, Some(_)) => return Err(failed("A translation needs its lang".into())),
+        (None, None) => None,
+    };
+
+    if !seen.insert((link.clone(), table.hidden())) {
+        return Err(failed(format!("Article {} is imported twice", link).into()));
+    }
+
+    // An article has moved only when this very file was imported into the
+    // other table, a link can be in both tables at once.
+    let other_file: Option<String> = conn.query_row("
+        SELECT
+            file
+        FROM
+            imported
+        WHERE
+            link=? AND hidden=?
+    ", params![link, !table.hidden()], |row| row.get(0)).optional()?;
+
+    if other_file.as_deref() == Some(&*file.to_string_lossy()) {
+        articles::delete(conn, Table::new(!table.hidden()), &link)?;
+    }
+
+    let existing: Option<(String, String, Option<String>, i64, i64, bool)> = conn.query_row(&format!("
+        SELECT
+            name, text, {}, date, lastmod, {}
+        FROM
+            {}
+        WHERE
+            link=?
+    ", if table.hidden() { "NULL" } else { "short_text" },
+       if table.hidden() { "0" } else { "dnshow" },
+       table.name()),
+    params![link], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))
+    .optional()?;
+
+    let mut stmt = conn.prepare("SELECT tag FROM tags WHERE link=? AND hidden=? ORDER BY tag")?;
+
+    let old_tags: Vec<String> = stmt
+        .query_map(params![link, table.hidden()], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    let mut tags = front_matter.tags.clone();
+
+    tags.sort();
+    tags.dedup();
+
+    let old_translation = articles::translation(conn, table, &link)?;
+
+    match existing {
+        None => {
+            articles::create(conn, table, &link, &front_matter.title, &text, short_text.as_deref(), front_matter.dnshow)?;
+
+            if let Some(date) = date {
+                conn.execute(&format!("UPDATE {} SET date=? WHERE link=?", table.name()), params![date, link])?;
+            }
+
+            if let Some(lastmod) = lastmod {
+                conn.execute(&format!("UPDATE {} SET lastmod=? WHERE link=?", table.name()), params![lastmod, link])?;
+            }
+
+            report.created.push(link.clone());
+        }
+
+        Some((name, old_text, old_short_text, old_date, old_lastmod, old_dnshow)) => {
+            let changed = name != front_matter.title
+                || old_text != text
+                || old_short_text != short_text
+                || date.map(|date| date != old_date).unwrap_or(false);
+
+            // A changed lastmod in the file wins, otherwise changes are
+            // dated now, so that editing an exported file is noticed.
+            let lastmod = lastmod.filter(|lastmod| *lastmod != old_lastmod);
+
+            if changed {
+                articles::set_text(conn, table, &link, &text)?;
+                articles::set_name(conn, table, &link, &front_matter.title)?;
+
+                if !table.hidden() {
+                    conn.execute("UPDATE articles SET short_text=? WHERE link=?", params![short_text, link])?;
+                }
+
+                if let Some(date) = date {
+                    conn.execute(&format!("UPDATE {} SET date=? WHERE link=?", table.name()), params![date, link])?;
+                }
+            }
+
+            if let Some(lastmod) = lastmod {
+                conn.execute(&format!("UPDATE {} SET lastmod=? WHERE link=?", table.name()), params![lastmod, link])?;
+            }
+
+            if !table.hidden() && old_dnshow != front_matter.dnshow {
+                articles::set_unlisted(conn, &link, front_matter.dnshow)?;
+            }
+
+            if changed || lastmod.is_some() || old_dnshow != front_matter.dnshow || old_tags != tags
+                || old_translation != translation {
+                report.updated.push(link.clone());
+            } else {
+                report.unchanged.push(link.clone());
+            }
+        }
+    }
+
+    conn.execute("DELETE FROM tags WHERE link=? AND hidden=?", params![link, table.hidden()])?;
+
+    for tag in &tags {
+        conn.execute("INSERT INTO tags (link, hidden, tag) VALUES (?, ?, ?)", params![link, table.hidden(), tag])?;
+    }
+
+    articles::set_translation(conn, table, &link,
+                              translation.as_ref().map(|(lang, key)| (lang.as_str(), key.as_str())))?;
+
+    conn.execute("
+        INSERT INTO imported (link, hidden, file)
+        VALUES (?, ?, ?)
+        ON CONFLICT(link, hidden) DO UPDATE SET file=excluded.file
+    ", params![link, table.hidden(), file.to_string_lossy()])?;
+
+    Ok(())
+}
+
+// link, name, text, short_text, date, lastmod, dnshow
+type ExportedRow = (String, String, String, Option<String>, i64, i64, bool);
+
+fn format_date(timestamp: i64) -> Option<String> {
+    DateTime::from_timestamp(timestamp, 0).map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+// Writes every article to a file of its own, `link.html`, or
+// `link.hidden.html` for hidden ones, since a link can be in both tables.
+// Returns the number of files written.
+pub fn export(conn: &Connection, dir: &Path) -> Result<usize, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+
+    let mut written = 0;
+
+    for table in &[Table::Articles, Table::Hidden] {
+        let mut stmt = conn.prepare(&format!("
+            SELECT
+                link, name, text, {}, date, lastmod, {}
+            FROM
+                {}
+            ORDER BY
+                link
+        ", if table.hidden() { "NULL" } else { "short_text" },
+           if table.hidden() { "0" } else { "dnshow" },
+           table.name()))?;
+
+        let rows: Vec<ExportedRow> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?,
+                                     row.get(4)?, row.get(5)?, row.get(6)?)))?
+            .collect::<Result<_, _>>()?;
+
+        let mut tags = conn.prepare("SELECT tag FROM tags WHERE link=? AND hidden=? ORDER BY tag")?;
+
+        for (link, name, text, short_text, date, lastmod, dnshow) in rows {
+            if link.is_empty() || link.contains('/') || link.starts_with('.') {
+                eprintln!("Article {:?} skipped, its link is not a file name", link);
+                continue;
+            }
+
+            let translation = articles::translation(conn, *table, &link)?;
+
+            let front_matter = FrontMatter {
+                link: Some(link.clone()),
+                title: name,
+                date: format_date(date),
+                lastmod: if lastmod > 0 { format_date(lastmod) } else { None },
+                tags: tags.query_map(params![link, table.hidden()], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?,
+                lang: translation.as_ref().map(|(lang, _)| lang.clone()),
+                translation: translation.map(|(_, key)| key).filter(|key| *key != link),
+                short_text,
+                hidden: table.hidden(),
+                dnshow,
+            };
+
+            let file = if table.hidden() { format!("{}.hidden.html", link) } else { format!("{}.html", link) };
+
+            fs::write(dir.join(file), format!("---\n{}---\n{}", serde_yaml::to_string(&front_matter)?, text))?;
+
+            written += 1;
+        }
+    }
+
+    Ok(written)
+}
+
+// Snapshot of the files in the content directory, taken to notice changes.
+fn files(dir: &Path) -> io::Result<Vec<(PathBuf, Option<SystemTime>, u64)>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if is_article_file(&path) {
+            let metadata = fs::metadata(&path)?;
+
+            files.push((path, metadata.modified().ok(), metadata.len()));
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+// Keeps the database in sync with the content directory. The page cache
+// notices the writes of this connection through data_version, and the
+// publisher picks up the new articles, so nothing else has to be told.
+pub fn watch(database: String, settings: Content) {
+    thread::spawn(move || {
+        let mut conn = match Connection::open(&database) {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Content watcher database opening failed: {}", e);
+                return;
+            }
+        };
+
+        let dir = Path::new(&settings.dir);
+        let mut last = None;
+        let mut last_error = String::new();
+
+        loop {
+            let result = files(dir).map_err(Box::<dyn Error>::from).and_then(|files| {
+                if last.as_ref() != Some(&files) {
+                    let report = import(&mut conn, dir, true, true)?;
+
+                    last = Some(files);
+                    log(&report);
+                }
+
+                Ok(())
+            });
+
+            // A missing directory would be logged every poll otherwise.
+            match result {
+                Ok(()) => last_error.clear(),
+                Err(e) if e.to_string() != last_error => {
+                    eprintln!("Content syncing from {} failed: {}", settings.dir, e);
+                    last_error = e.to_string();
+                }
+                Err(_) => {}
+            }
+
+            thread::sleep(Duration::from_secs(settings.poll_interval));
+        }
+    });
+}
+
+fn log(report: &Report) {
+    for (status, links) in &[("created", &report.created),
+                             ("updated", &report.updated),
+                             ("deleted", &report.deleted)] {
+        for link in links.iter() {
+            eprintln!("Content: {} {}", status, link);
+        }
+    }
+
+    for failure in &report.failed {
+        eprintln!("Content syncing skipped {}", failure);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use rusqlite::{ params, Connection };
+
+    use crate::articles::{ self, Table };
+    use crate::schema;
+    use super::{ export, import, parse, parse_date };
+
+    fn database() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        schema::migrate(&mut conn).unwrap();
+        conn
+    }
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn yaml_front_matter() {
+        let document = parse("\u{feff}---\ntitle: Hello\ntags: [a, b]\nhidden: true\n---\nBody\n---\n")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(document.front_matter.title, "Hello");
+        assert_eq!(document.front_matter.tags, vec!["a", "b"]);
+        assert!(document.front_matter.hidden);
+        assert_eq!(document.body, "Body\n---\n");
+    }
+
+    #[test]
+    fn toml_front_matter() {
+        let document = parse("+++\ntitle = \"Hello\"\ndate = 2022-05-01T10:00:00Z\n+++\nBody")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(document.front_matter.date.as_deref(), Some("2022-05-01T10:00:00Z"));
+        assert_eq!(document.body, "Body");
+    }
+
+    #[test]
+    fn without_front_matter() {
+        assert!(parse("# Just markdown\n").unwrap().is_none());
+        assert!(parse("---\ntitle: Open\n").is_err());
+        assert!(parse("---\nno title\n---\n").is_err());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("2022-05-01T10:00:00+03:00").unwrap(), 1651388400);
+        assert_eq!(parse_date("2022-05-01T07:00:00Z").unwrap(), 1651388400);
+        assert_eq!(parse_date("2022-05-01 07:00:00").unwrap(), 1651388400);
+        assert_eq!(parse_date(" 2022-05-01 07:00 ").unwrap(), 1651388400);
+        assert_eq!(parse_date("2022-05-01").unwrap(), 1651363200);
+        assert!(parse_date("01.05.2022").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut conn = database();
+        let dir = tempfile::tempdir().unwrap();
+
+        // The same link in both tables, with a comment on the public one.
+        articles::create(&conn, Table::Articles, "same", "Public", "<p>Public</p>", Some("Short"), false).unwrap();
+        articles::create(&conn, Table::Hidden, "same", "Hidden", "<p>Hidden</p>", None, false).unwrap();
+        articles::create(&conn, Table::Articles, "other", "Other", "<p>Other</p>", None, true).unwrap();
+        articles::set_translation(&conn, Table::Articles, "other", Some(("en", "same"))).unwrap();
+
+        conn.execute("INSERT INTO tags (link, hidden, tag) VALUES ('other', 0, 'tag')", []).unwrap();
+        conn.execute("
+            INSERT INTO comments (article, author, text, date, status)
+            VALUES ('same', 'Reader', 'Hi', 0, 'approved')
+        ", []).unwrap();
+
+        assert_eq!(export(&conn, dir.path()).unwrap(), 3);
+
+        for _ in 0..2 {
+            let report = import(&mut conn, dir.path(), true, false).unwrap();
+
+            let mut unchanged = report.unchanged.clone();
+
+            unchanged.sort();
+
+            assert_eq!(unchanged, vec!["other", "same", "same"]);
+            assert!(report.created.is_empty() && report.updated.is_empty() && report.deleted.is_empty());
+        }
+
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM articles"), 2);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM hidden_articles"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM comments"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM tags"), 1);
+        assert_eq!(articles::translation(&conn, Table::Articles, "other").unwrap(),
+                   Some(("en".to_owned(), "same".to_owned())));
+    }
+
+    #[test]
+    fn moved_file() {
+        let mut conn = database();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("moving.md");
+
+        fs::write(&file, "---\ntitle: Moving\n---\nText\n").unwrap();
+        import(&mut conn, dir.path(), true, false).unwrap();
+
+        fs::write(&file, "---\ntitle: Moving\nhidden: true\n---\nText\n").unwrap();
+        let report = import(&mut conn, dir.path(), true, false).unwrap();
+
+        assert_eq!(report.created, vec!["moving"]);
+        assert!(!articles::exists(&conn, Table::Articles, "moving").unwrap());
+        assert!(articles::exists(&conn, Table::Hidden, "moving").unwrap());
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM imported"), 1);
+
+        let hidden: bool = conn.query_row("SELECT hidden FROM imported WHERE link=?", params!["moving"],
+                                          |row| row.get(0)).unwrap();
+
+        assert!(hidden);
+    }
+}
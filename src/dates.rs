@@ -0,0 +1,111 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fmt::Write;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::collections::HashMap;
+use chrono::{ DateTime, Locale, Utc };
+use chrono_tz::Tz;
+use fluent_bundle::{ FluentArgs, FluentValue };
+use tera::{ Filter, Value };
+
+use crate::i18n::Catalog;
+
+// Dates reach templates in RFC 3339, which `<time datetime>` wants, and
+// these filters format them for people. The pattern and the locale of month
+// names are in the catalog, as `date-format` and `date-locale`.
+
+// `local_date(lang=lang, tz=tz)`
+pub struct LocalDate(pub Arc<Catalog>);
+
+// `relative_date(lang=lang)`, like "3 days ago". Cached pages would keep
+// showing the time they were rendered at, so it suits uncached ones.
+pub struct RelativeDate(pub Arc<Catalog>);
+
+impl Filter for LocalDate {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let date = parse(value)?;
+        let lang = lang(&self.0, args);
+
+        let timezone = match args.get("tz").and_then(Value::as_str) {
+            Some(timezone) => timezone.parse::<Tz>()
+                .map_err(|e| format!("local_date got a wrong `tz`: {}", e))?,
+            None => Tz::UTC,
+        };
+
+        Ok(Value::String(format(&self.0, lang, date, timezone)))
+    }
+}
+
+impl Filter for RelativeDate {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let date = parse(value)?;
+
+        Ok(Value::String(relative(&self.0, lang(&self.0, args), date, Utc::now())))
+    }
+}
+
+pub fn format(catalog: &Catalog, lang: &str, date: DateTime<Utc>, timezone: Tz) -> String {
+    let pattern = catalog.translate(lang, "date-format", None);
+
+    let locale = Locale::try_from(catalog.translate(lang, "date-locale", None).as_str())
+        .unwrap_or(Locale::POSIX);
+
+    // A broken pattern fails only when written, and `to_string` would panic.
+    let mut formatted = String::new();
+
+    match write!(formatted, "{}", date.with_timezone(&timezone).format_localized(&pattern, locale)) {
+        Ok(()) => formatted,
+        Err(_) => {
+            eprintln!("Date format {:?} of {} is wrong", pattern, lang);
+            date.to_rfc3339()
+        }
+    }
+}
+
+pub fn relative(catalog: &Catalog, lang: &str, date: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - date).num_seconds().max(0);
+
+    let (key, count) = match seconds {
+        0..=59 => ("relative-now", 0),
+        60..=3599 => ("relative-minutes", seconds / 60),
+        3600..=86399 => ("relative-hours", seconds / 3600),
+        86400..=2591999 => ("relative-days", seconds / 86400),
+        2592000..=31535999 => ("relative-months", seconds / 2592000),
+        _ => ("relative-years", seconds / 31536000),
+    };
+
+    let mut args = FluentArgs::new();
+
+    args.set("count", FluentValue::from(count));
+
+    catalog.translate(lang, key, Some(&args))
+}
+
+fn parse(value: &Value) -> tera::Result<DateTime<Utc>> {
+    let date = value.as_str().ok_or("Dates are expected to be strings")?;
+
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|e| format!("Date {:?} is not in RFC 3339: {}", date, e).into())
+}
+
+fn lang<'a>(catalog: &'a Catalog, args: &'a HashMap<String, Value>) -> &'a str {
+    args.get("lang")
+        .and_then(Value::as_str)
+        .unwrap_or_else(|| catalog.default_locale())
+}
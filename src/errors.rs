@@ -19,7 +19,6 @@ use std::fmt;
 use std::error::Error;
 use std::sync::PoisonError;
 use actix_web::{ web, HttpResponse, HttpRequest };
-use tera::Context;
 use crate::state::State;
 
 #[macro_export]
@@ -37,7 +36,7 @@ macro_rules! try_500 {
                 }
 
                 eprintln!("Error 500: {}", e);
-                return error_500(temp_req, temp_state)
+                return error_500(temp_req, temp_state, &e.to_string())
             },
         }
     }};
@@ -82,6 +81,12 @@ impl From<std::io::Error> for MyError {
     }
 }
 
+impl From<rusqlite::Error> for MyError {
+    fn from(err: rusqlite::Error) -> Self {
+        MyError { details: err.to_string() }
+    }
+}
+
 impl From<geoip2::Error> for MyError {
     fn from(_: geoip2::Error) -> Self {
         MyError { details: "geoip2 error".to_owned() }
@@ -96,40 +101,41 @@ impl MyError {
 
 pub async fn error_404(req: HttpRequest,
                        state: web::Data<State<'_>>) -> HttpResponse {
-    let mut context = Context::new();
-    let auth = try_500!(state.auth.read(), state, req);
+    let context = try_500!(state.context(&req), state, req);
 
-    context.insert("authorized", &auth.authorized(&req));
-
-    return HttpResponse::NotFound()
-        .body(try_500!(state.tera.render("404.html", &context), state, req));
+    HttpResponse::NotFound()
+        .body(try_500!(state.tera.render("404.html", &context), state, req))
 }
 
 pub fn error_401_russia(req: HttpRequest,
                         state: web::Data<State<'_>>) -> HttpResponse {
-    let mut context = Context::new();
-    let auth = try_500!(state.auth.read(), state, req);
-
-    context.insert("authorized", &auth.authorized(&req));
+    let context = try_500!(state.context(&req), state, req);
 
-    return HttpResponse::Unauthorized()
-        .body(try_500!(state.tera.render("401_russia.html", &context), state, req));
+    HttpResponse::Unauthorized()
+        .body(try_500!(state.tera.render("401_russia.html", &context), state, req))
 }
 
 pub fn error_emergency_500() -> HttpResponse {
-    return HttpResponse::InternalServerError().body("500 Internal Server Error");
+    HttpResponse::InternalServerError().body("500 Internal Server Error")
 }
 
+// In development mode the error itself is shown, and without templates,
+// since they may be what is broken.
 pub fn error_500(req: HttpRequest,
-                 state: web::Data<State>) -> HttpResponse {
-    let mut context = Context::new();
-    let auth = try_500!(state.auth.read(), state, req);
+                 state: web::Data<State>,
+                 error: &str) -> HttpResponse {
+    if state.config.development {
+        return HttpResponse::InternalServerError()
+            .content_type("text/html; charset=utf-8")
+            .body(format!("<!DOCTYPE html>\n<title>500</title>\n<pre>{}</pre>\n",
+                          tera::escape_html(error)));
+    }
 
-    context.insert("authorized", &auth.authorized(&req));
+    let context = try_500!(state.context(&req), state, req);
 
     if let Ok(body) = state.tera.render("500.html", &context) {
-        return HttpResponse::InternalServerError().body(body);
+        HttpResponse::InternalServerError().body(body)
     } else {
-        return error_emergency_500();
+        error_emergency_500()
     }
 }
@@ -0,0 +1,159 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use rusqlite::Connection;
+use tera::{ Context, Tera };
+
+use crate::assets::{ AssetManifest, STATIC_DIR };
+use crate::config::Config;
+use crate::i18n::{ Alternate, Catalog };
+use crate::post::Post;
+use crate::{ images, pages, sitemap, templates, webmention };
+
+// Pages are written as `index.html` in a directory named by their path, so
+// `/articles/link` comes from `articles/link/index.html` and any server that
+// serves directory indexes finds it without rewrites. Forms and everything
+// else that needs the server are left out.
+pub fn export(config: Arc<Config>, out: &Path) -> Result<(), Box<dyn Error>> {
+    let conn = Connection::open(&config.database)?;
+    let assets = Arc::new(AssetManifest::build(Path::new(STATIC_DIR))?);
+    let catalog = Arc::new(Catalog::load(&config.i18n)?);
+    let tera = templates::load(&config, &assets, &catalog)?;
+
+    let mut context = Context::new();
+
+    // Static hosting can not pick a locale, so the mirror has the default one.
+    context.insert("authorized", &false);
+    context.insert("lang", catalog.default_locale());
+    context.insert("lang_prefix", "");
+    context.insert("alternates", &Vec::<Alternate>::new());
+    context.insert("tz", config.dates.timezone.name());
+    context.insert("reader_timezone", &false);
+
+    let posts = pages::published_articles(&conn)?;
+    let mut list = context.clone();
+
+    list.insert("posts", &posts);
+
+    write(out, "index.html", &tera.render("posts.html", &list)?)?;
+    write(out, "articles/index.html", &tera.render("posts.html", &list)?)?;
+    write(out, "404.html", &tera.render("404.html", &context)?)?;
+    write(out, "sitemap.xml", &tera.render("sitemap.xml", &sitemap::context(&conn, None, &config.host)?)?)?;
+
+    for post in articles(&conn, "articles")? {
+        let mut page = context.clone();
+
+        page.insert("webmentions", &webmention::for_article(&conn, &post.link)?);
+
+        export_post(out, "articles", &post, &tera, page)?;
+    }
+
+    for post in articles(&conn, "hidden_articles")? {
+        export_post(out, "articles/hidden", &post, &tera, context.clone())?;
+    }
+
+    copy_dir(Path::new(STATIC_DIR), out)?;
+
+    for (path, hash) in assets.files() {
+        let hashed = out.join("assets").join(hash).join(path);
+
+        if let Some(parent) = hashed.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::copy(Path::new(STATIC_DIR).join(path), hashed)?;
+        images::export(&config, path, out)?;
+    }
+
+    let media = Path::new(&config.media.dir);
+
+    if media.is_dir() {
+        copy_dir(media, &out.join("media").join("files"))?;
+    }
+
+    Ok(())
+}
+
+// Hidden articles are not listed anywhere, but whoever has the link can
+// read them, so they are exported as well.
+fn articles(conn: &Connection, table: &str) -> Result<Vec<Post>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(&format!("
+        SELECT *
+        FROM
+            {}
+    ", table))?;
+
+    let mut rows = stmt.query([])?;
+    let mut posts: Vec<Post> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        posts.push(Post::from_row(row)?);
+    }
+
+    Ok(posts)
+}
+
+fn export_post(out: &Path,
+               dir: &str,
+               post: &Post,
+               tera: &Tera,
+               mut context: Context) -> Result<(), Box<dyn Error>> {
+    if post.link.is_empty() || post.link.contains('/') || post.link.starts_with('.') {
+        eprintln!("Article {:?} skipped, its link is not a file name", post.link);
+        return Ok(());
+    }
+
+    context.insert("post", post);
+
+    write(out, &format!("{}/{}/index.html", dir, post.link), &tera.render("post.html", &context)?)
+}
+
+fn write(out: &Path, path: &str, contents: &str) -> Result<(), Box<dyn Error>> {
+    let path = out.join(path);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
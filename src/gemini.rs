@@ -0,0 +1,265 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::thread;
+use std::error::Error;
+use std::io::{ self, Read, Write };
+use std::net::{ IpAddr, TcpListener, TcpStream };
+use std::sync::Arc;
+use std::time::Duration;
+use geoip2::{ Country, Reader };
+use openssl::ssl::SslAcceptor;
+use percent_encoding::percent_decode_str;
+use rusqlite::Connection;
+use url::Url;
+
+use crate::assets::encode_path;
+use crate::config::Config;
+use crate::i18n::Catalog;
+use crate::markup;
+use crate::pages;
+use crate::post::Post;
+use crate::tls::Certificates;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REQUEST_LENGTH: usize = 1024;
+const MIME: &str = "text/gemini; charset=utf-8";
+
+struct Response {
+    status: u8,
+    meta: String,
+    body: String,
+}
+
+impl Response {
+    fn success(body: String, lang: &str) -> Response {
+        Response { status: 20, meta: format!("{}; lang={}", MIME, lang), body }
+    }
+
+    fn failure(status: u8, meta: &str) -> Response {
+        Response { status, meta: meta.to_owned(), body: String::new() }
+    }
+}
+
+// A read-only mirror of the articles for Gemini clients. Every connection
+// gets a thread and a database connection of its own, like the HTTP workers.
+pub struct Gemini {
+    config: Arc<Config>,
+    catalog: Arc<Catalog>,
+    acceptor: SslAcceptor,
+    geoip_reader: Option<Reader<'static, Country<'static>>>,
+}
+
+impl Gemini {
+    pub fn new(config: Arc<Config>,
+               catalog: Arc<Catalog>,
+               certificates: &Arc<Certificates>,
+               geoip_reader: Option<Reader<'static, Country<'static>>>) -> Result<Gemini, Box<dyn Error>> {
+        let acceptor = certificates.acceptor()?.build();
+
+        Ok(Gemini { config, catalog, acceptor, geoip_reader })
+    }
+
+    fn translate(&self, key: &str) -> String {
+        self.catalog.translate(self.catalog.default_locale(), key, None)
+    }
+
+    pub fn listen(self: &Arc<Self>, address: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let gemini = self.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Gemini connection accepting failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let gemini = gemini.clone();
+
+                thread::spawn(move || {
+                    if let Err(e) = gemini.serve(stream) {
+                        eprintln!("Gemini request failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let ip = stream.peer_addr()?.ip();
+        let mut stream = self.acceptor.accept(stream).map_err(|e| e.to_string())?;
+
+        let response = match read_request(&mut stream)? {
+            Some(request) => {
+                let response = self.respond(&request, ip).unwrap_or_else(|e| {
+                    eprintln!("Gemini response to {} failed: {}", request, e);
+                    Response::failure(40, &self.translate("internal-error"))
+                });
+
+                eprintln!("gemini {} \"{}\" {}", ip, request, response.status);
+
+                response
+            }
+            None => Response::failure(59, "Bad request"),
+        };
+
+        stream.write_all(format!("{} {}\r\n", response.status, response.meta).as_bytes())?;
+        stream.write_all(response.body.as_bytes())?;
+        stream.shutdown()?;
+
+        Ok(())
+    }
+
+    fn respond(&self, request: &str, ip: IpAddr) -> Result<Response, Box<dyn Error>> {
+        if pages::blocked_country(&self.geoip_reader, ip) {
+            return Ok(Response::failure(50, &self.translate("country-blocked")));
+        }
+
+        let url = match Url::parse(request) {
+            Ok(url) => url,
+            Err(_) => return Ok(Response::failure(59, "Bad request")),
+        };
+
+        let own_host = url.host_str()
+            .map(|host| self.config.hosts().any(|known| known.eq_ignore_ascii_case(host)))
+            .unwrap_or(false);
+
+        if url.scheme() != "gemini" || !own_host {
+            return Ok(Response::failure(53, "Proxy request refused"));
+        }
+
+        let segments: Vec<&str> = url.path_segments()
+            .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+            .unwrap_or_default();
+
+        let conn = Connection::open(&self.config.database)?;
+
+        match segments.as_slice() {
+            [] | ["articles"] => self.index(&conn),
+            ["articles", link] => {
+                let link = percent_decode_str(link).decode_utf8()?;
+
+                match pages::find_article(&conn, &link)? {
+                    Some(post) => Ok(self.article(&post)),
+                    None => Ok(Response::failure(51, "Not found")),
+                }
+            }
+            _ => Ok(Response::failure(51, "Not found")),
+        }
+    }
+
+    fn index(&self, conn: &Connection) -> Result<Response, Box<dyn Error>> {
+        let mut body = format!("# {}\n\n", self.translate("site-title"));
+
+        let timezone = &self.config.dates.timezone;
+
+        for post in pages::published_articles(conn)? {
+            body.push_str(&format!("=> /articles/{} {}", encode_path(&post.link), post.name));
+
+            if let Some(date) = &post.date {
+                body.push_str(&format!(" ({})", date.0.with_timezone(timezone).format("%d.%m.%Y")));
+            }
+
+            body.push('\n');
+        }
+
+        body.push_str(&format!("\n=> https://{}/ {}\n", self.config.host, self.translate("site-on-web")));
+
+        Ok(Response::success(body, self.catalog.default_locale()))
+    }
+
+    fn article(&self, post: &Post) -> Response {
+        let web_url = format!("https://{}/articles/{}", self.config.host, encode_path(&post.link));
+        let base = Url::parse(&web_url).ok();
+
+        let text = markup::gemtext(&markup::blocks(&post.text, &self.translate("image")), |url| self.resolve(base.as_ref(), url));
+
+        let mut body = format!("# {}\n\n{}\n", post.name, text);
+
+        let timezone = &self.config.dates.timezone;
+
+        if let Some(date) = &post.date {
+            body.push_str(&format!("{}\n\n", date.0.with_timezone(timezone).format("%d.%m.%Y %H:%M %Z")));
+        }
+
+        body.push_str(&format!("=> / {}\n=> {} {}\n",
+                               self.translate("to-index"),
+                               web_url,
+                               self.translate("article-on-web")));
+
+        Response::success(body, self.catalog.default_locale())
+    }
+
+    // Links to other articles stay in Gemini, everything else points to the
+    // web version of the site.
+    fn resolve(&self, base: Option<&Url>, url: &str) -> String {
+        let url = match base.and_then(|base| base.join(url).ok()) {
+            Some(url) => url,
+            None => return url.to_owned(),
+        };
+
+        let own_host = url.host_str()
+            .map(|host| self.config.hosts().any(|known| known.eq_ignore_ascii_case(host)))
+            .unwrap_or(false);
+
+        if own_host && url.path().starts_with("/articles/") && !url.path().starts_with("/articles/hidden/") {
+            url.path().to_owned()
+        } else {
+            url.to_string()
+        }
+    }
+}
+
+// A request is an absolute URL of at most 1024 bytes followed by CRLF.
+fn read_request(stream: &mut impl Read) -> Result<Option<String>, Box<dyn Error>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 256];
+
+    loop {
+        if let Some(end) = request.windows(2).position(|window| window == b"\r\n") {
+            request.truncate(end);
+            break;
+        }
+
+        if request.len() > MAX_REQUEST_LENGTH + 1 {
+            return Ok(None);
+        }
+
+        let read = stream.read(&mut buffer)?;
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    if request.len() > MAX_REQUEST_LENGTH {
+        return Ok(None);
+    }
+
+    Ok(String::from_utf8(request).ok())
+}
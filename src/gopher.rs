@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::thread;
+use std::error::Error;
+use std::io::{ self, Read, Write };
+use std::net::{ IpAddr, TcpListener, TcpStream };
+use std::sync::Arc;
+use std::time::Duration;
+use geoip2::{ Country, Reader };
+use percent_encoding::percent_decode_str;
+use rusqlite::Connection;
+use url::Url;
+
+use crate::assets::encode_path;
+use crate::config::Config;
+use crate::i18n::Catalog;
+use crate::markup;
+use crate::pages;
+use crate::post::Post;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_SELECTOR_LENGTH: usize = 1024;
+const WIDTH: usize = 70;
+
+// A read-only mirror of the articles for Gopher clients: a menu of the
+// published articles, each of them a text file.
+pub struct Gopher {
+    config: Arc<Config>,
+    catalog: Arc<Catalog>,
+    geoip_reader: Option<Reader<'static, Country<'static>>>,
+}
+
+impl Gopher {
+    pub fn new(config: Arc<Config>,
+               catalog: Arc<Catalog>,
+               geoip_reader: Option<Reader<'static, Country<'static>>>) -> Gopher {
+        Gopher { config, catalog, geoip_reader }
+    }
+
+    fn translate(&self, key: &str) -> String {
+        self.catalog.translate(self.catalog.default_locale(), key, None)
+    }
+
+    pub fn listen(self: &Arc<Self>, address: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let port = listener.local_addr()?.port();
+        let gopher = self.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Gopher connection accepting failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let gopher = gopher.clone();
+
+                thread::spawn(move || {
+                    if let Err(e) = gopher.serve(stream, port) {
+                        eprintln!("Gopher request failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    fn serve(&self, mut stream: TcpStream, port: u16) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let ip = stream.peer_addr()?.ip();
+
+        let response = match read_selector(&mut stream)? {
+            Some(selector) => {
+                let response = self.respond(&selector, ip, port).unwrap_or_else(|e| {
+                    eprintln!("Gopher response to {} failed: {}", selector, e);
+                    self.error(&self.translate("internal-error"), port)
+                });
+
+                eprintln!("gopher {} \"{}\"", ip, selector);
+
+                response
+            }
+            None => self.error(&self.translate("bad-request"), port),
+        };
+
+        stream.write_all(response.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn respond(&self, selector: &str, ip: IpAddr, port: u16) -> Result<String, Box<dyn Error>> {
+        if pages::blocked_country(&self.geoip_reader, ip) {
+            return Ok(self.error(&self.translate("country-blocked"), port));
+        }
+
+        let segments: Vec<&str> = selector.split('/').filter(|segment| !segment.is_empty()).collect();
+        let conn = Connection::open(&self.config.database)?;
+
+        match segments.as_slice() {
+            [] | ["articles"] => self.menu(&conn, port),
+            ["articles", link] => {
+                let link = percent_decode_str(link).decode_utf8()?;
+
+                match pages::find_article(&conn, &link)? {
+                    Some(post) => Ok(self.article(&post)),
+                    None => Ok(self.error(&self.translate("page-not-found"), port)),
+                }
+            }
+            _ => Ok(self.error(&self.translate("page-not-found"), port)),
+        }
+    }
+
+    fn item(&self, kind: char, text: &str, selector: &str, port: u16) -> String {
+        format!("{}{}\t{}\t{}\t{}\r\n", kind, text.replace('\t', " "), selector, self.config.host, port)
+    }
+
+    fn error(&self, text: &str, port: u16) -> String {
+        self.item('3', text, "", port) + ".\r\n"
+    }
+
+    fn menu(&self, conn: &Connection, port: u16) -> Result<String, Box<dyn Error>> {
+        let mut menu = self.item('i', &self.translate("site-title"), "", port);
+
+        menu.push_str(&self.item('i', "", "", port));
+
+        let timezone = &self.config.dates.timezone;
+
+        for post in pages::published_articles(conn)? {
+            let title = match &post.date {
+                Some(date) => format!("{} ({})", post.name, date.0.with_timezone(timezone).format("%d.%m.%Y")),
+                None => post.name.clone(),
+            };
+
+            menu.push_str(&self.item('0', &title, &format!("/articles/{}", encode_path(&post.link)), port));
+        }
+
+        menu.push_str(&self.item('i', "", "", port));
+        menu.push_str(&self.item('h', &self.translate("site-on-web"), &format!("URL:https://{}/", self.config.host), port));
+        menu.push_str(".\r\n");
+
+        Ok(menu)
+    }
+
+    fn article(&self, post: &Post) -> String {
+        let web_url = format!("https://{}/articles/{}", self.config.host, encode_path(&post.link));
+        let base = Url::parse(&web_url).ok();
+
+        let resolve = |url: &str| base.as_ref()
+            .and_then(|base| base.join(url).ok())
+            .map(|url| url.to_string())
+            .unwrap_or_else(|| url.to_owned());
+
+        let mut blocks = vec![markup::Block::Heading(1, post.name.clone())];
+
+        blocks.extend(markup::blocks(&post.text, &self.translate("image")));
+
+        let mut text = markup::plain(&blocks, WIDTH, resolve);
+
+        let timezone = &self.config.dates.timezone;
+
+        if let Some(date) = &post.date {
+            text.push_str(&format!("\n{}\n", date.0.with_timezone(timezone).format("%d.%m.%Y %H:%M %Z")));
+        }
+
+        text.push_str(&format!("\n{}: {}\n", self.translate("article-on-web"), web_url));
+
+        // Text ends with a lone dot, so lines starting with one are doubled.
+        let mut response: String = text.lines()
+            .map(|line| if line.starts_with('.') { format!(".{}\r\n", line) } else { format!("{}\r\n", line) })
+            .collect();
+
+        response.push_str(".\r\n");
+
+        response
+    }
+}
+
+// A selector is a line ended by CRLF, anything after a tab is a search query
+// which is of no use here.
+fn read_selector(stream: &mut impl Read) -> Result<Option<String>, Box<dyn Error>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 256];
+
+    loop {
+        if let Some(end) = request.iter().position(|&byte| byte == b'\n') {
+            request.truncate(end);
+            break;
+        }
+
+        if request.len() > MAX_SELECTOR_LENGTH + 1 {
+            return Ok(None);
+        }
+
+        let read = stream.read(&mut buffer)?;
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    if request.ends_with(b"\r") {
+        request.pop();
+    }
+
+    if let Some(tab) = request.iter().position(|&byte| byte == b'\t') {
+        request.truncate(tab);
+    }
+
+    if request.len() > MAX_SELECTOR_LENGTH {
+        return Ok(None);
+    }
+
+    Ok(String::from_utf8(request).ok())
+}
@@ -0,0 +1,337 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{ self, Read };
+use std::error::Error;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs };
+use std::sync::Arc;
+use std::time::Duration;
+use native_tls::TlsConnector;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+pub struct Request {
+    pub method: &'static str,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+impl Request {
+    pub fn get(url: &str) -> Request {
+        Request {
+            method: "GET",
+            url: url.to_owned(),
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn post(url: &str, content_type: &str, body: Vec<u8>) -> Request {
+        Request {
+            method: "POST",
+            url: url.to_owned(),
+            headers: vec![("Content-Type".to_owned(), content_type.to_owned())],
+            body: Some(body),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    // Where the request ended up after redirects.
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn headers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers.iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+// Everything talking to other sites goes through this, so it can be pointed
+// at a local server or replaced altogether. Calls block.
+pub trait HttpClient: Send + Sync {
+    fn send(&self, request: Request) -> Result<Response, Box<dyn Error>>;
+}
+
+pub struct UreqClient {
+    agent: ureq::Agent,
+}
+
+impl UreqClient {
+    // Private addresses are only for testing against servers on this
+    // machine or network.
+    pub fn new(user_agent: &str, allow_private: bool) -> Result<UreqClient, Box<dyn Error>> {
+        let mut builder = ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .redirects(5)
+            .user_agent(user_agent)
+            .tls_connector(Arc::new(TlsConnector::new()?));
+
+        if !allow_private {
+            builder = builder.resolver(PublicResolver);
+        }
+
+        Ok(UreqClient { agent: builder.build() })
+    }
+}
+
+// URLs come from other sites, so nothing but public addresses is reached
+// through them. Every connection, each redirect hop included, resolves here,
+// so a name can not point somewhere else between the check and the connect.
+struct PublicResolver;
+
+impl ureq::Resolver for PublicResolver {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?
+            .filter(|addr| is_global(addr.ip()))
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("{} has no public address", netloc)));
+        }
+
+        Ok(addrs)
+    }
+}
+
+pub fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => match (ip.to_ipv4_mapped(), nat64(ip)) {
+            (Some(embedded), _) | (_, Some(embedded)) => is_global_v4(embedded),
+            _ => is_global_v6(ip),
+        },
+    }
+}
+
+// DNS64 gives IPv4-only sites addresses of 64:ff9b::/96 ending in the IPv4 one.
+fn nat64(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+
+    if octets[..12] == [0, 0x64, 0xff, 0x9b, 0, 0, 0, 0, 0, 0, 0, 0] {
+        Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    } else {
+        None
+    }
+}
+
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space of carrier-grade NAT.
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking.
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved.
+        || a >= 240)
+}
+
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local.
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link local and the deprecated site local.
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation.
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // Deprecated IPv4-compatible addresses.
+        || segments[..6].iter().all(|segment| *segment == 0))
+}
+
+impl HttpClient for UreqClient {
+    fn send(&self, request: Request) -> Result<Response, Box<dyn Error>> {
+        let mut builder = self.agent.request(request.method, &request.url);
+
+        for (name, value) in &request.headers {
+            builder = builder.set(name, value);
+        }
+
+        let result = match request.body {
+            Some(body) => builder.send_bytes(&body),
+            None => builder.call(),
+        };
+
+        // Error statuses are still responses here, callers decide about them.
+        let response = match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => return Err(e.into()),
+        };
+
+        let headers = response.headers_names().into_iter()
+            .flat_map(|name| {
+                response.all(&name).into_iter()
+                    .map(|value| (name.clone(), value.to_owned()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let status = response.status();
+        let url = response.get_url().to_owned();
+        let mut body = Vec::new();
+
+        response.into_reader().take(MAX_BODY_SIZE).read_to_end(&mut body)?;
+
+        Ok(Response { status, url, headers, body })
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use std::error::Error;
+    use std::sync::Mutex;
+    use std::collections::HashMap;
+
+    use super::{ HttpClient, Request, Response };
+
+    // Answers with canned pages and remembers what was asked.
+    #[derive(Default)]
+    pub struct FakeClient {
+        pub pages: HashMap<String, Response>,
+        pub requests: Mutex<Vec<Request>>,
+    }
+
+    impl FakeClient {
+        pub fn page(mut self, url: &str, final_url: &str, headers: &[(&str, &str)], body: &str) -> FakeClient {
+            self.pages.insert(url.to_owned(), Response {
+                status: 200,
+                url: final_url.to_owned(),
+                headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+                body: body.as_bytes().to_vec(),
+            });
+
+            self
+        }
+    }
+
+    impl HttpClient for FakeClient {
+        fn send(&self, request: Request) -> Result<Response, Box<dyn Error>> {
+            let response = match self.pages.get(&request.url) {
+                Some(page) => Response {
+                    status: page.status,
+                    url: page.url.clone(),
+                    headers: page.headers.clone(),
+                    body: page.body.clone(),
+                },
+
+                None => Response { status: 404, url: request.url.clone(), headers: Vec::new(), body: Vec::new() },
+            };
+
+            self.requests.lock().unwrap().push(request);
+
+            Ok(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::io::{ Read, Write };
+    use std::net::{ IpAddr, TcpListener };
+
+    use super::{ is_global, HttpClient, Request, UreqClient };
+
+    fn global(ip: &str) -> bool {
+        is_global(ip.parse::<IpAddr>().unwrap())
+    }
+
+    // Answers a single request on a local port.
+    fn local_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/page", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello");
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn private_addresses_when_allowed() {
+        let client = UreqClient::new("test", true).unwrap();
+        let response = client.send(Request::get(&local_server())).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "hello");
+    }
+
+    #[test]
+    fn private_addresses_refused_by_default() {
+        let client = UreqClient::new("test", false).unwrap();
+        let error = client.send(Request::get(&local_server())).err().unwrap();
+
+        assert!(error.to_string().contains("has no public address"), "{}", error);
+    }
+
+    #[test]
+    fn public_addresses() {
+        assert!(global("93.184.216.34"));
+        assert!(global("2606:2800:220:1:248:1893:25c8:1946"));
+        assert!(global("::ffff:93.184.216.34"));
+        assert!(global("64:ff9b::93.184.216.34"));
+    }
+
+    #[test]
+    fn internal_addresses() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+                   "100.64.0.1", "0.0.0.0", "255.255.255.255", "224.0.0.1", "240.0.0.1",
+                   "::1", "::", "fc00::1", "fd12:3456::1", "fe80::1", "ff02::1",
+                   "::ffff:127.0.0.1", "::ffff:169.254.169.254", "64:ff9b::10.0.0.1", "::127.0.0.1"] {
+            assert!(!global(ip), "{} is not global", ip);
+        }
+    }
+}
@@ -0,0 +1,458 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fs;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::task::{ Context, Poll };
+use actix_web::{ Error, HttpMessage, HttpRequest };
+use actix_web::cookie::Cookie;
+use actix_web::dev::{ Service, ServiceRequest, ServiceResponse, Transform };
+use actix_web::http::{ HeaderValue, PathAndQuery, Uri, header };
+use fluent_bundle::{ FluentArgs, FluentResource, FluentValue };
+use fluent_bundle::concurrent::FluentBundle;
+use futures::future::{ ok, FutureExt, LocalBoxFuture, Ready };
+use serde::Serialize;
+use tera::{ Function, Value };
+use unic_langid::LanguageIdentifier;
+
+use crate::config::I18n;
+
+// Interface strings are kept in Fluent files named after their locales,
+// like `locales/en.ftl`. A page is in the locale of its `/en` path prefix,
+// else of the `lang` cookie, else the best one of Accept-Language.
+pub struct Catalog {
+    default: String,
+    bundles: HashMap<String, FluentBundle<FluentResource>>,
+}
+
+#[derive(Clone)]
+pub struct Locale {
+    pub lang: String,
+    pub prefixed: bool,
+}
+
+#[derive(Serialize)]
+pub struct Alternate {
+    pub lang: String,
+    pub href: String,
+}
+
+impl Catalog {
+    pub fn load(settings: &I18n) -> Result<Catalog, String> {
+        let mut bundles = HashMap::new();
+        let entries = fs::read_dir(&settings.dir)
+            .map_err(|e| format!("{}: {}", settings.dir, e))?;
+
+        for entry in entries {
+            let path = entry.map_err(|e| format!("{}: {}", settings.dir, e))?.path();
+
+            let locale = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(locale) if path.extension() == Some("ftl".as_ref()) => locale.to_owned(),
+                _ => continue,
+            };
+
+            let langid: LanguageIdentifier = locale.parse()
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+            let source = fs::read_to_string(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+            let resource = FluentResource::try_new(source)
+                .map_err(|(_, errors)| format!("{}: {}", path.display(), errors[0]))?;
+
+            let mut bundle = FluentBundle::new_concurrent(vec![langid]);
+
+            // Isolation marks would end up in titles and attributes.
+            bundle.set_use_isolating(false);
+            bundle.add_resource(resource)
+                .map_err(|errors| format!("{}: {}", path.display(), errors[0]))?;
+
+            bundles.insert(locale, bundle);
+        }
+
+        if !bundles.contains_key(&settings.default_locale) {
+            return Err(format!("{} has no {}.ftl for the default locale",
+                               settings.dir, settings.default_locale));
+        }
+
+        Ok(Catalog { default: settings.default_locale.clone(), bundles })
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default
+    }
+
+    pub fn locales(&self) -> Vec<&str> {
+        let mut locales: Vec<&str> = self.bundles.keys().map(String::as_str).collect();
+
+        locales.sort_unstable();
+        locales
+    }
+
+    pub fn has(&self, locale: &str) -> bool {
+        self.bundles.contains_key(locale)
+    }
+
+    // A missing translation falls back to the default locale and then to
+    // the key itself, so it shows on the page instead of failing it.
+    pub fn translate(&self, locale: &str, key: &str, args: Option<&FluentArgs>) -> String {
+        for locale in &[locale, self.default.as_str()] {
+            let bundle = match self.bundles.get(*locale) {
+                Some(bundle) => bundle,
+                None => continue,
+            };
+
+            if let Some(pattern) = bundle.get_message(key).and_then(|message| message.value()) {
+                let mut errors = Vec::new();
+                let value = bundle.format_pattern(pattern, args, &mut errors);
+
+                for e in errors {
+                    eprintln!("Translating {} to {} failed: {}", key, locale, e);
+                }
+
+                return value.into_owned();
+            }
+        }
+
+        key.to_owned()
+    }
+
+    // The locale with the highest q-value in an Accept-Language header.
+    // A regional tag like `en-US` is served by `en` when there is no `en-US`.
+    pub fn negotiate(&self, accept_language: &str) -> Option<&str> {
+        let mut best: Option<(f32, &str)> = None;
+
+        for item in accept_language.split(',') {
+            let mut parts = item.split(';');
+            let tag = parts.next().unwrap_or("").trim();
+
+            let quality = parts
+                .find_map(|part| part.trim().strip_prefix("q="))
+                .and_then(|quality| quality.parse().ok())
+                .unwrap_or(1.0);
+
+            let primary = tag.split('-').next().unwrap_or("");
+
+            let locale = self.bundles.keys()
+                .find(|locale| locale.eq_ignore_ascii_case(tag))
+                .or_else(|| self.bundles.keys().find(|locale| locale.eq_ignore_ascii_case(primary)));
+
+            if let Some(locale) = locale {
+                if quality > 0.0 && best.is_none_or(|(best, _)| quality > best) {
+                    best = Some((quality, locale));
+                }
+            }
+        }
+
+        best.map(|(_, locale)| locale)
+    }
+
+    // Articles can be in languages without interface strings, these have
+    // no prefix to go to.
+    pub fn url(&self, host: &str, lang: &str, path: &str) -> String {
+        if self.has(lang) {
+            format!("https://{}/{}{}", host, lang, path)
+        } else {
+            format!("https://{}{}", host, path)
+        }
+    }
+
+    // The same page in every locale, for `hreflang` links.
+    pub fn alternates(&self, host: &str, path: &str) -> Vec<Alternate> {
+        if self.bundles.len() < 2 {
+            return Vec::new();
+        }
+
+        let mut alternates: Vec<Alternate> = self.locales().into_iter()
+            .map(|locale| Alternate {
+                lang: locale.to_owned(),
+                href: self.url(host, locale, path),
+            })
+            .collect();
+
+        alternates.push(Alternate {
+            lang: "x-default".to_owned(),
+            href: format!("https://{}{}", host, path),
+        });
+
+        alternates
+    }
+}
+
+impl Locale {
+    // Pages with a prefix link to prefixed pages, so they are kept apart
+    // from the pages in the same locale chosen by the cookie or header.
+    pub fn variant(&self) -> String {
+        if self.prefixed {
+            format!("/{}", self.lang)
+        } else {
+            self.lang.clone()
+        }
+    }
+
+    pub fn prefix(&self) -> String {
+        if self.prefixed {
+            format!("/{}", self.lang)
+        } else {
+            String::new()
+        }
+    }
+}
+
+// Arguments of a message with a single variable, like `{ $max }`.
+pub fn arg<'a>(name: &'a str, value: impl Into<FluentValue<'a>>) -> FluentArgs<'a> {
+    let mut args = FluentArgs::new();
+
+    args.set(name, value);
+    args
+}
+
+// Marks a request whose response was made for its locale, only such
+// responses get `Vary: Accept-Language`.
+struct LocaleRead;
+
+pub fn locale(req: &HttpRequest) -> Option<Locale> {
+    let locale = req.extensions().get::<Locale>().cloned();
+
+    req.extensions_mut().insert(LocaleRead);
+    locale
+}
+
+// `t(key="sign-in", lang=lang)`, the other arguments go to Fluent.
+pub struct Translate(pub Arc<Catalog>);
+
+impl Function for Translate {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let key = args.get("key")
+            .and_then(Value::as_str)
+            .ok_or("t requires a string `key` argument")?;
+
+        let lang = args.get("lang")
+            .and_then(Value::as_str)
+            .unwrap_or_else(|| self.0.default_locale());
+
+        let mut fluent_args = FluentArgs::new();
+
+        for (name, value) in args {
+            let value = match value {
+                _ if name == "key" || name == "lang" => continue,
+                Value::Number(number) => FluentValue::from(number.as_f64().unwrap_or_default()),
+                Value::String(string) => FluentValue::from(string.as_str()),
+                value => FluentValue::from(value.to_string()),
+            };
+
+            fluent_args.set(name.as_str(), value);
+        }
+
+        Ok(Value::String(self.0.translate(lang, key, Some(&fluent_args))))
+    }
+}
+
+pub struct Locales {
+    catalog: Arc<Catalog>,
+}
+
+impl Locales {
+    pub fn new(catalog: Arc<Catalog>) -> Locales {
+        Locales { catalog }
+    }
+}
+
+impl<S, B> Transform<S> for Locales
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LocalesMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(LocalesMiddleware { service, catalog: self.catalog.clone() })
+    }
+}
+
+pub struct LocalesMiddleware<S> {
+    service: S,
+    catalog: Arc<Catalog>,
+}
+
+impl<S, B> Service for LocalesMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let cookie = req.cookie("lang").map(|cookie| cookie.value().to_owned());
+
+        let locale = match strip_prefix(&self.catalog, req.path()) {
+            Some((lang, path)) => {
+                rewrite_path(&mut req, &path);
+                Locale { lang, prefixed: true }
+            }
+            None => {
+                let accept_language = req.headers().get(header::ACCEPT_LANGUAGE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| self.catalog.negotiate(value));
+
+                let lang = cookie.as_deref()
+                    .filter(|lang| self.catalog.has(lang))
+                    .or(accept_language)
+                    .unwrap_or_else(|| self.catalog.default_locale())
+                    .to_owned();
+
+                Locale { lang, prefixed: false }
+            }
+        };
+
+        req.extensions_mut().insert(locale.clone());
+
+        let negotiated = !locale.prefixed && self.catalog.locales().len() > 1;
+        let fut = self.service.call(req);
+
+        async move {
+            let mut res = fut.await?;
+
+            // Remembers the choice, so that links without a prefix keep it.
+            if locale.prefixed && cookie.as_deref() != Some(&locale.lang) {
+                let cookie = Cookie::build("lang", locale.lang)
+                    .path("/")
+                    .permanent()
+                    .finish();
+
+                if let Err(e) = res.response_mut().add_cookie(&cookie) {
+                    eprintln!("Locale cookie setting failed: {}", e);
+                }
+            }
+
+            if negotiated && res.request().extensions().contains::<LocaleRead>() {
+                res.headers_mut().append(header::VARY, HeaderValue::from_static("Accept-Language"));
+            }
+
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
+
+// `/en/articles/x` is `/articles/x` in `en`, and `/en` is the index.
+fn strip_prefix(catalog: &Catalog, path: &str) -> Option<(String, String)> {
+    let rest = path.strip_prefix('/')?;
+
+    let (lang, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+
+    if catalog.has(lang) {
+        Some((lang.to_owned(), path.to_owned()))
+    } else {
+        None
+    }
+}
+
+fn rewrite_path(req: &mut ServiceRequest, path: &str) {
+    let mut parts = req.head().uri.clone().into_parts();
+
+    let path_and_query = match parts.path_and_query.as_ref().and_then(|pq| pq.query()) {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_owned(),
+    };
+
+    let uri = PathAndQuery::from_maybe_shared(path_and_query).ok()
+        .and_then(|path_and_query| {
+            parts.path_and_query = Some(path_and_query);
+            Uri::from_parts(parts).ok()
+        });
+
+    if let Some(uri) = uri {
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use actix_web::{ test, web, App, HttpRequest };
+    use actix_web::http::header;
+    use fluent_bundle::concurrent::FluentBundle;
+
+    use super::{ locale, Catalog, Locales };
+
+    fn catalog(locales: &[&str]) -> Catalog {
+        let bundles: HashMap<_, _> = locales.iter()
+            .map(|locale| (locale.to_string(), FluentBundle::new_concurrent(vec![locale.parse().unwrap()])))
+            .collect();
+
+        Catalog { default: locales[0].to_owned(), bundles }
+    }
+
+    #[test]
+    fn highest_quality() {
+        let catalog = catalog(&["ru", "en"]);
+
+        assert_eq!(catalog.negotiate("ru;q=0.4, en;q=0.8"), Some("en"));
+        assert_eq!(catalog.negotiate("de, ru;q=0.5, en;q=0.3"), Some("ru"));
+        assert_eq!(catalog.negotiate("de, fr;q=0.9"), None);
+    }
+
+    #[test]
+    fn regional_fallback() {
+        let catalog = catalog(&["ru", "en", "pt-BR"]);
+
+        assert_eq!(catalog.negotiate("en-US"), Some("en"));
+        assert_eq!(catalog.negotiate("EN-gb, ru;q=0.9"), Some("en"));
+        assert_eq!(catalog.negotiate("pt-br"), Some("pt-BR"));
+        assert_eq!(catalog.negotiate("pt-PT"), None);
+    }
+
+    #[test]
+    fn zero_quality_is_refused() {
+        let catalog = catalog(&["ru", "en"]);
+
+        assert_eq!(catalog.negotiate("en;q=0, ru;q=0.1"), Some("ru"));
+        assert_eq!(catalog.negotiate("en-US;q=0.0"), None);
+    }
+
+    #[actix_rt::test]
+    async fn vary_only_when_localized() {
+        let mut app = test::init_service(App::new()
+            .wrap(Locales::new(Arc::new(catalog(&["ru", "en"]))))
+            .route("/page", web::get().to(|req: HttpRequest| async move { locale(&req).unwrap().lang }))
+            .route("/file", web::get().to(|| async { "file" }))).await;
+
+        for (path, vary) in &[("/page", Some("Accept-Language")), ("/en/page", None), ("/file", None)] {
+            let res = test::call_service(&mut app, test::TestRequest::get().uri(path).to_request()).await;
+
+            assert_eq!(res.headers().get(header::VARY).map(|value| value.to_str().unwrap()), *vary, "{}", path);
+        }
+    }
+}
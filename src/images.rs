@@ -0,0 +1,273 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::error::Error;
+use std::io::{ BufWriter, Write };
+use std::os::unix::fs::PermissionsExt;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::collections::HashMap;
+use actix_files::NamedFile;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::http::{ HeaderValue, header };
+use image::{ ImageFormat, imageops::FilterType };
+use regex::{ Captures, Regex };
+use tera::{ Filter, Function, Value };
+
+use crate::assets::{ AssetManifest, STATIC_DIR, encode_path };
+use crate::compression::resolve;
+use crate::config::Config;
+use crate::state::State;
+use crate::errors::*;
+
+const SIZES: &str = "(max-width: 800px) 100vw, 800px";
+
+pub async fn image(req: HttpRequest,
+                   state: web::Data<State<'_>>,
+                   path: web::Path<(u32, u32, String)>) -> HttpResponse {
+    let (width, height, path) = path.into_inner();
+    let widths = &state.config.images.widths;
+
+    let allowed = |size: u32| size == 0 || widths.contains(&size);
+
+    if !allowed(width) || !allowed(height) || (width == 0 && height == 0) {
+        return error_404(req, state).await;
+    }
+
+    let source = match resolve(Path::new(STATIC_DIR), &path) {
+        Some(source) if source.is_file() && source_format(&source).is_some() => source,
+        _ => return error_404(req, state).await,
+    };
+
+    let webp = req.headers().get_all(header::ACCEPT)
+        .filter_map(|accept| accept.to_str().ok())
+        .any(|accept| accept.contains("image/webp"));
+
+    let cached = cached_path(&state.config, width, height, &path, webp);
+    let cached_temp = cached.clone();
+
+    let result = web::block(move || {
+        resize(&source, &cached_temp, width, height, webp).map_err(|e| e.to_string())
+    }).await;
+
+    try_500!(result, state, req);
+
+    let file = try_500!(NamedFile::open(&cached), state, req);
+    let mut response = try_500!(file.disable_content_disposition().into_response(&req), state, req);
+
+    let headers = response.headers_mut();
+
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=86400"));
+    headers.append(header::VARY, HeaderValue::from_static("Accept"));
+
+    response
+}
+
+fn source_format(path: &Path) -> Option<ImageFormat> {
+    match ImageFormat::from_path(path).ok()? {
+        format @ (ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP) => {
+            Some(format)
+        }
+
+        _ => None,
+    }
+}
+
+fn cached_path(config: &Config, width: u32, height: u32, path: &str, webp: bool) -> PathBuf {
+    let mut cached = Path::new(&config.images.cache_dir).join(format!("{}x{}", width, height));
+
+    cached.push(path.trim_start_matches('/'));
+
+    if webp {
+        let mut name = cached.into_os_string();
+        name.push(".webp");
+        cached = PathBuf::from(name);
+    }
+
+    cached
+}
+
+fn resize(source: &Path,
+          cached: &Path,
+          width: u32,
+          height: u32,
+          webp: bool) -> Result<(), Box<dyn Error>> {
+    let source_modified = fs::metadata(source)?.modified()?;
+
+    let up_to_date = fs::metadata(cached)
+        .and_then(|metadata| metadata.modified())
+        .map(|modified| modified >= source_modified)
+        .unwrap_or(false);
+
+    if up_to_date {
+        return Ok(());
+    }
+
+    let image = image::open(source)?;
+
+    let width = if width == 0 { u32::MAX } else { width };
+    let height = if height == 0 { u32::MAX } else { height };
+
+    // Never upscale, only fit into the requested box.
+    let image = if image.width() > width || image.height() > height {
+        image.resize(width, height, FilterType::Lanczos3)
+    } else {
+        image
+    };
+
+    let format = if webp {
+        ImageFormat::WebP
+    } else {
+        match source_format(source) {
+            Some(ImageFormat::Jpeg) => ImageFormat::Jpeg,
+            _ => ImageFormat::Png,
+        }
+    };
+
+    let dir = cached.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    // Each request writes a file of its own and renames it into place, so
+    // concurrent requests for a variant never see or mix up half a file.
+    let mut temp = tempfile::Builder::new()
+        .suffix(".tmp")
+        .permissions(fs::Permissions::from_mode(0o644))
+        .tempfile_in(dir)?;
+
+    let image = if format == ImageFormat::Jpeg {
+        image::DynamicImage::ImageRgb8(image.to_rgb8())
+    } else {
+        image
+    };
+
+    {
+        let mut writer = BufWriter::new(temp.as_file_mut());
+        image.write_to(&mut writer, format)?;
+        writer.flush()?;
+    }
+
+    temp.persist(cached)?;
+
+    Ok(())
+}
+
+// Writes the sizes srcset offers for a static image under `out`, the same
+// way the server would resize them on request.
+pub fn export(config: &Config, path: &str, out: &Path) -> Result<(), Box<dyn Error>> {
+    let source = match resolve(Path::new(STATIC_DIR), path) {
+        Some(source) if source.is_file() && source_format(&source).is_some() => source,
+        _ => return Ok(()),
+    };
+
+    let (source_width, _) = image::image_dimensions(&source)?;
+
+    for width in config.images.widths.iter().filter(|width| **width < source_width) {
+        let resized = out.join("img").join(format!("{}x0", width)).join(path);
+
+        resize(&source, &resized, *width, 0, false)?;
+    }
+
+    Ok(())
+}
+
+pub fn srcset(config: &Config, assets: &AssetManifest, path: &str) -> Option<String> {
+    let path = path.trim_start_matches('/');
+    let file = resolve(Path::new(STATIC_DIR), path)?;
+
+    source_format(&file)?;
+
+    let (source_width, _) = image::image_dimensions(&file).ok()?;
+
+    let mut candidates: Vec<String> = config.images.widths.iter()
+        .filter(|width| **width < source_width)
+        .map(|width| format!("/img/{}x0/{} {}w", width, encode_path(path), width))
+        .collect();
+
+    candidates.push(format!("{} {}w", assets.url(path), source_width));
+
+    Some(candidates.join(", "))
+}
+
+pub struct Srcset {
+    pub config: Arc<Config>,
+    pub assets: Arc<AssetManifest>,
+}
+
+impl Function for Srcset {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let path = args.get("path")
+            .and_then(Value::as_str)
+            .ok_or("srcset requires a string `path` argument")?;
+
+        Ok(Value::String(srcset(&self.config, &self.assets, path).unwrap_or_default()))
+    }
+
+    // Paths in the srcset are percent-encoded, so it is safe inside attributes.
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+// Adds srcset to local images in article HTML that do not have one yet.
+pub struct ResponsiveImages {
+    pub config: Arc<Config>,
+    pub assets: Arc<AssetManifest>,
+    img: Regex,
+    src: Regex,
+}
+
+impl ResponsiveImages {
+    pub fn new(config: Arc<Config>, assets: Arc<AssetManifest>) -> ResponsiveImages {
+        ResponsiveImages {
+            config,
+            assets,
+            img: Regex::new(r"(?i)<img\b[^>]*>").expect("Invalid img regex"),
+            src: Regex::new(r#"(?i)\bsrc\s*=\s*"(/[^"]+)""#).expect("Invalid src regex"),
+        }
+    }
+}
+
+impl Filter for ResponsiveImages {
+    fn filter(&self, value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+        let html = value.as_str().ok_or("responsive_images requires a string")?;
+
+        let html = self.img.replace_all(html, |caps: &Captures| {
+            let tag = &caps[0];
+
+            if tag.to_ascii_lowercase().contains("srcset") {
+                return tag.to_owned();
+            }
+
+            let srcset = self.src.captures(tag)
+                .and_then(|src| srcset(&self.config, &self.assets, &src[1]));
+
+            match srcset {
+                Some(srcset) => {
+                    let end = if tag.ends_with("/>") { tag.len() - 2 } else { tag.len() - 1 };
+
+                    format!("{} srcset=\"{}\" sizes=\"{}\"{}",
+                            tag[..end].trim_end(), srcset, SIZES, &tag[end..])
+                }
+
+                None => tag.to_owned(),
+            }
+        });
+
+        Ok(Value::String(html.into_owned()))
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::error::Error;
+use std::sync::Arc;
+use lettre::{ FileTransport, Message, SmtpTransport, Transport };
+use lettre::message::{ Mailbox, header::{ ContentType, Header, HeaderName, HeaderValue } };
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{ Tls, TlsParameters };
+
+use crate::config::{ Mail, MailTls, MailTransport };
+
+pub struct Letter {
+    pub to: Mailbox,
+    pub reply_to: Option<Mailbox>,
+    pub subject: String,
+    pub body: String,
+    pub unsubscribe: Option<String>,
+}
+
+// Sending blocks until the transport accepts the letter, so call it from
+// web::block or a background thread.
+pub trait Mailer: Send + Sync {
+    fn send(&self, letter: Letter) -> Result<(), Box<dyn Error>>;
+}
+
+struct LettreMailer<T> {
+    from: Mailbox,
+    transport: T,
+}
+
+impl<T> Mailer for LettreMailer<T>
+where
+    T: Transport + Send + Sync,
+    T::Error: Error + 'static,
+{
+    fn send(&self, letter: Letter) -> Result<(), Box<dyn Error>> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .to(letter.to)
+            .subject(letter.subject);
+
+        if let Some(reply_to) = letter.reply_to {
+            builder = builder.reply_to(reply_to);
+        }
+
+        if let Some(unsubscribe) = letter.unsubscribe {
+            builder = builder
+                .header(ListUnsubscribe(format!("<{}>", unsubscribe)))
+                .header(ListUnsubscribePost);
+        }
+
+        let message = builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(letter.body)?;
+
+        self.transport.send(&message)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct ListUnsubscribe(String);
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(ListUnsubscribe(s.to_owned()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+// Lets mail clients unsubscribe with a single POST, see RFC 8058.
+#[derive(Clone)]
+struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(_: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(ListUnsubscribePost)
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), "List-Unsubscribe=One-Click".to_owned())
+    }
+}
+
+fn smtp_transport(config: &Mail) -> Result<SmtpTransport, Box<dyn Error>> {
+    let tls = match config.tls {
+        MailTls::None => Tls::None,
+        MailTls::Starttls => Tls::Required(TlsParameters::new(config.host.clone())?),
+        MailTls::Tls => Tls::Wrapper(TlsParameters::new(config.host.clone())?),
+    };
+
+    let mut builder = SmtpTransport::builder_dangerous(&config.host)
+        .port(config.port)
+        .tls(tls);
+
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    Ok(builder.build())
+}
+
+pub fn mailer(config: &Mail) -> Result<Arc<dyn Mailer>, Box<dyn Error>> {
+    let from = config.from.parse()?;
+
+    Ok(match config.transport {
+        MailTransport::Smtp => Arc::new(LettreMailer { from, transport: smtp_transport(config)? }),
+
+        // Every letter becomes an .eml file, handy for development and tests.
+        MailTransport::File => {
+            fs::create_dir_all(&config.dir)?;
+
+            Arc::new(LettreMailer { from, transport: FileTransport::new(&config.dir) })
+        }
+    })
+}
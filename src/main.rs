@@ -23,78 +23,279 @@ mod config;
 mod pages;
 mod sitemap;
 mod auth;
+mod tls;
+mod security;
+mod caching;
+mod page_cache;
+mod compression;
+mod assets;
+mod images;
+mod schema;
+mod media;
+mod comments;
+mod spam;
+mod mail;
+mod contact;
+mod publish;
+mod newsletter;
+mod http;
+mod webmention;
+mod activitypub;
+mod markup;
+mod gemini;
+mod gopher;
+mod export;
+mod users;
+mod articles;
+mod content;
+mod templates;
+mod i18n;
+mod dates;
+mod cli;
 
 use std::fs;
 use std::path::Path;
-use std::sync::RwLock;
+use std::cell::Cell;
+use std::sync::{ Arc, RwLock };
 use actix_web::{ web, App, middleware, HttpServer, HttpResponse, HttpRequest };
-use actix_files::Files;
 use geoip2::{ Country, Reader };
-use openssl::ssl::{ SslAcceptor, SslFiletype, SslMethod };
 
 use errors::*;
 use state::State;
-use config::Config;
+use config::{ Config, Listener };
 use pages::*;
 use sitemap::sitemap;
+use tls::Certificates;
+use templates::Templates;
+use i18n::{ Catalog, Locales };
+use security::SecurityHeaders;
+use page_cache::{ PageCache, page_cache_stats };
+use compression::{ Compress, static_file };
+use assets::{ AssetManifest, asset };
+use images::image;
+use media::{ media, media_upload, media_delete, media_file };
+use comments::{ comment_submit, moderation, moderate };
+use spam::{ FormGuard, form_token };
+use contact::{ contact, contact_submit, messages, message_delete };
+use publish::Publisher;
+use newsletter::{ Digest, newsletter, subscribe, confirm, unsubscribe_form, unsubscribe };
+use http::UreqClient;
+use webmention::{ Verifier, webmention };
+use activitypub::{ Federation, Delivery, webfinger, actor, outbox, followers, inbox };
+use gemini::Gemini;
+use gopher::Gopher;
+use cli::{ Cli, Command };
+use clap::Parser;
 use crate::auth::*;
 
 async fn redirect(req: HttpRequest,
-                  host: web::Data<String>) -> HttpResponse {
+                  config: web::Data<Arc<Config>>) -> HttpResponse {
     let uri_parts: actix_web::http::uri::Parts = req.uri().to_owned().into_parts();
     let path_and_query = try_emergency_500!(
         uri_parts.path_and_query.ok_or("Can not get path_and_query")
     );
 
-    return HttpResponse::PermanentRedirect().header(
+    let connection_info = req.connection_info();
+    let requested_host = connection_info.host().split(':').next().unwrap_or_default();
+
+    let host = if config.hosts().any(|host| host.eq_ignore_ascii_case(requested_host)) {
+        requested_host
+    } else {
+        &config.host
+    };
+
+    HttpResponse::PermanentRedirect().header(
         "Location",
         format!("https://{}{}",
-            host.get_ref(),
+            host,
             path_and_query.as_str()
         )
-    ).finish();
+    ).finish()
 }
 
 fn init_reader<'a, P: AsRef<Path>>(path: P) -> Result<Reader<'a, Country<'a>>, MyError> {
     Ok(Reader::<Country>::from_bytes(Box::leak(fs::read(path)?.into_boxed_slice()))?)
 }
 
+fn load_reader<'a, P: AsRef<Path>>(path: P) -> Option<Reader<'a, Country<'a>>> {
+    match init_reader(path) {
+        Ok(result) => Some(result),
+        Err(e) => {
+            eprintln!("geoip2 init error: {}", e);
+            None
+        },
+    }
+}
+
+fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    use std::sync::Arc;
-
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
 
-    let config = Arc::new(Config::read_from_file("config.json")
+    let cli = Cli::parse();
+
+    match cli.command {
+        None | Some(Command::Serve) => {}
+        Some(Command::Precompress { dir }) => return compression::precompress_dir(&dir),
+        Some(command) => {
+            if let Err(e) = cli::run(&cli.config, command) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+
+            return Ok(());
+        }
+    }
+
+    let config = Arc::new(Config::read_from_file(&cli.config)
         .expect("Config reading failed"));
 
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
-        .expect("SSL Acceptor Builder creating failed");
+    let mut conn = rusqlite::Connection::open(&config.database)
+        .expect("Database opening failed");
 
-    builder.set_private_key_file(&config.priv_key_file, SslFiletype::PEM)
-        .expect("SSL private key file setting failed");
+    schema::migrate(&mut conn)
+        .expect("Database migration failed");
 
-    builder.set_certificate_chain_file(&config.cert_chain_file)
-        .expect("SSL certificate chain file setting failed");
+    drop(conn);
 
-    let config_temp = config.clone();
+    let catalog = Arc::new(Catalog::load(&config.i18n)
+        .expect("Locale catalog loading failed"));
 
-    HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::Logger::default())
-            .data(config_temp.host.clone())
-            .default_service(web::route().to(redirect))
-    })
-    .bind(format!("{}:80", config.host))?
-    .run();
+    let mailer = config.mail.as_ref().map(|mail| mail::mailer(mail)
+        .expect("Mailer creation failed"));
+
+    let user_agent = format!("website (+https://{})", config.host);
+    let client: Arc<dyn http::HttpClient> = Arc::new(UreqClient::new(&user_agent, config.allow_private_addresses)
+        .expect("HTTP client creation failed"));
+
+    let webmentions = Arc::new(Verifier::start(config.database.clone(), client.clone()));
+
+    let mut publisher = Publisher::new(config.database.clone());
+
+    publisher.add(Box::new(webmention::Sender { config: config.clone(), client: client.clone() }));
+
+    let federation = config.activitypub.clone().map(|settings| {
+        Arc::new(Federation::new(config.clone(), settings, client.clone())
+            .expect("ActivityPub initialization failed"))
+    });
+
+    if let Some(federation) = &federation {
+        publisher.add(Box::new(Delivery(federation.clone())));
+    }
+
+    if let Some(mailer) = &mailer {
+        publisher.add(Box::new(Digest {
+            config: config.clone(),
+            catalog: catalog.clone(),
+            mailer: mailer.clone(),
+        }));
+    }
+
+    publisher.watch();
+
+    if let Some(content) = config.content.clone() {
+        content::watch(config.database.clone(), content);
+    }
+
+    let certificates = if config.uses_tls() {
+        let certificates = Arc::new(Certificates::load(config.clone())
+            .expect("SSL certificates loading failed"));
+
+        tls::watch(certificates.clone());
+
+        Some(certificates)
+    } else {
+        None
+    };
+
+    let redirect_addresses: Vec<String> = config.listeners().into_iter()
+        .filter_map(|listener| match listener {
+            Listener::Redirect { address } => Some(address),
+            _ => None,
+        })
+        .collect();
+
+    if !redirect_addresses.is_empty() {
+        let config_temp = config.clone();
+
+        let mut server = HttpServer::new(move || {
+            App::new()
+                .wrap(middleware::Logger::default())
+                .data(config_temp.clone())
+                .default_service(web::route().to(redirect))
+        });
+
+        for address in redirect_addresses {
+            server = server.bind(address)?;
+        }
+
+        server.run();
+    }
+
+    let gemini_addresses: Vec<String> = config.listeners().into_iter()
+        .filter_map(|listener| match listener {
+            Listener::Gemini { address } => Some(address),
+            _ => None,
+        })
+        .collect();
+
+    if !gemini_addresses.is_empty() {
+        let certificates = certificates.as_ref()
+            .expect("SSL certificates are not loaded");
+
+        let gemini = Arc::new(Gemini::new(config.clone(), catalog.clone(), certificates,
+                                          load_reader(&config.geoip_db_file))
+            .expect("Gemini server creation failed"));
+
+        for address in gemini_addresses {
+            gemini.listen(&address)?;
+        }
+    }
+
+    let gopher_addresses: Vec<String> = config.listeners().into_iter()
+        .filter_map(|listener| match listener {
+            Listener::Gopher { address } => Some(address),
+            _ => None,
+        })
+        .collect();
+
+    if !gopher_addresses.is_empty() {
+        let gopher = Arc::new(Gopher::new(config.clone(), catalog.clone(),
+                                          load_reader(&config.geoip_db_file)));
+
+        for address in gopher_addresses {
+            gopher.listen(&address)?;
+        }
+    }
 
     let config_temp = config.clone();
+    let page_cache = Arc::new(PageCache::new(config.page_cache_size));
+    let assets = Arc::new(AssetManifest::build(Path::new(assets::STATIC_DIR))?);
+    let form_guard = Arc::new(FormGuard::new(config.clone())
+        .expect("Form guard creation failed"));
+
+    let template_generation = if config.development {
+        Some(templates::watch(&config.templates, page_cache.clone()))
+    } else {
+        None
+    };
+
+    let mut server = HttpServer::new(move || {
+        let tera = Templates::new(config_temp.clone(), assets.clone(), catalog.clone(),
+                                  template_generation.clone())
+            .expect("Tera template rendering failed");
 
-    HttpServer::new(move || {
         let state = State {
-            tera: tera::Tera::new(&config_temp.templates)
-                .expect("Tera template rendering failed"),
+            tera,
 
             conn: rusqlite::Connection::open(&config_temp.database)
                 .expect("Database opening failed"),
@@ -104,16 +305,22 @@ async fn main() -> std::io::Result<()> {
             auth: RwLock::new(Auth::new(config_temp.token.clone())
                 .expect("Auth creation failed")),
 
-            geoip_reader: match init_reader(&config_temp.geoip_db_file) {
-                Ok(result) => Some(result),
-                Err(e) => {
-                    eprintln!("geoip2 init error: {}", e.to_string());
-                    None
-                },
-            }
+            geoip_reader: load_reader(&config_temp.geoip_db_file),
+
+            page_cache: page_cache.clone(),
+            assets: assets.clone(),
+            catalog: catalog.clone(),
+            data_version: Cell::new(0),
+            form_guard: form_guard.clone(),
+            mailer: mailer.clone(),
+            webmentions: webmentions.clone(),
+            activitypub: federation.clone(),
         };
 
         App::new()
+            .wrap(Compress)
+            .wrap(SecurityHeaders::new(config_temp.clone()))
+            .wrap(Locales::new(catalog.clone()))
             .wrap(middleware::Logger::default())
             .data(state)
             .service(web::resource("/articles/{link}/")
@@ -122,6 +329,9 @@ async fn main() -> std::io::Result<()> {
             .service(web::resource("/articles/{link}")
                 .route(web::get().to(article_index))
             )
+            .service(web::resource("/articles/{link}/comments")
+                .route(web::post().to(comment_submit))
+            )
             .service(web::resource("/articles/hidden/{link}/")
                 .route(web::get().to(hidden_article_redirect))
             )
@@ -153,18 +363,110 @@ async fn main() -> std::io::Result<()> {
             .service(web::resource("/deauth")
                 .route(web::get().to(deauth))
             )
+            .service(web::resource("/forms/token")
+                .route(web::get().to(form_token))
+            )
+            .service(web::resource("/comments")
+                .route(web::get().to(moderation))
+            )
+            .service(web::resource("/comments/{id}/{action}")
+                .route(web::post().to(moderate))
+            )
+            .service(web::resource("/contact")
+                .route(web::get().to(contact))
+                .route(web::post().to(contact_submit))
+            )
+            .service(web::resource("/.well-known/webfinger")
+                .route(web::get().to(webfinger))
+            )
+            .service(web::resource("/actor")
+                .route(web::get().to(actor))
+            )
+            .service(web::resource("/actor/outbox")
+                .route(web::get().to(outbox))
+            )
+            .service(web::resource("/actor/followers")
+                .route(web::get().to(followers))
+            )
+            .service(web::resource("/actor/inbox")
+                .route(web::post().to(inbox))
+            )
+            .service(web::resource("/webmention")
+                .route(web::post().to(webmention))
+            )
+            .service(web::resource("/newsletter")
+                .route(web::get().to(newsletter))
+                .route(web::post().to(subscribe))
+            )
+            .service(web::resource("/newsletter/confirm")
+                .route(web::get().to(confirm))
+            )
+            .service(web::resource("/newsletter/unsubscribe")
+                .route(web::get().to(unsubscribe_form))
+                .route(web::post().to(unsubscribe))
+            )
+            .service(web::resource("/messages")
+                .route(web::get().to(messages))
+            )
+            .service(web::resource("/messages/{id}/delete")
+                .route(web::post().to(message_delete))
+            )
+            .service(web::resource("/media")
+                .route(web::get().to(media))
+                .route(web::post().to(media_upload))
+            )
+            .service(web::resource("/media/{id}/delete")
+                .route(web::post().to(media_delete))
+            )
+            .service(web::resource("/media/files/{file}")
+                .route(web::get().to(media_file))
+            )
+            .service(web::resource("/cache")
+                .route(web::get().to(page_cache_stats))
+            )
             .service(web::resource("/sitemap.xml")
                 .route(web::get().to(sitemap))
             )
             .service(web::resource("/")
                 .route(web::get().to(index))
             )
-            .service(Files::new("/", "static/"))
+            .service(web::resource(r"/img/{width:\d+}x{height:\d+}/{path:.*}")
+                .route(web::get().to(image))
+            )
+            .service(web::resource("/assets/{hash}/{path:.*}")
+                .route(web::get().to(asset))
+            )
+            .service(web::resource("/{path:.*}")
+                .route(web::get().to(static_file))
+                .route(web::head().to(static_file))
+            )
             .default_service(
                 web::get().to(error_404)
             )
-    })
-    .bind_openssl(format!("{}:443", config.host), builder)?
-    .run()
-    .await
+    });
+
+    for listener in config.listeners() {
+        server = match listener {
+            Listener::Https { address } => {
+                let builder = certificates.as_ref()
+                    .expect("SSL certificates are not loaded")
+                    .acceptor()
+                    .expect("SSL Acceptor Builder creating failed");
+
+                server.bind_openssl(address, builder)?
+            }
+
+            Listener::Http { address } => server.bind(address)?,
+            Listener::Unix { path } => {
+                remove_stale_socket(&path)?;
+                server.bind_uds(path)?
+            }
+
+            Listener::Redirect { .. }
+            | Listener::Gemini { .. }
+            | Listener::Gopher { .. } => server,
+        };
+    }
+
+    server.run().await
 }
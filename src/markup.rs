@@ -0,0 +1,391 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::borrow::Cow;
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::{
+    BufferQueue, Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+use pulldown_cmark::{ html, Options, Parser };
+
+// Articles reduced to what line based formats like gemtext can show. Links
+// are taken out of the text and follow the block they were in.
+pub enum Block {
+    Heading(u8, String),
+    Paragraph(String),
+    Item(String),
+    Quote(String),
+    Preformatted(String),
+    Link { url: String, text: String },
+}
+
+// Images without a description are linked by the `image` label.
+pub fn blocks(text: &str, image: &str) -> Vec<Block> {
+    let sink = Sink { image: image.to_owned(), ..Sink::default() };
+    let mut queue = BufferQueue::new();
+    let mut tokenizer = Tokenizer::new(sink, TokenizerOpts::default());
+
+    queue.push_back(StrTendril::from_slice(&to_html(text)));
+    let _ = tokenizer.feed(&mut queue);
+    tokenizer.end();
+
+    let mut sink = tokenizer.sink;
+
+    sink.flush();
+    sink.blocks
+}
+
+// Articles are mostly written in HTML, anything else is taken for markdown.
+fn to_html(text: &str) -> Cow<'_, str> {
+    if text.trim_start().starts_with('<') {
+        return Cow::Borrowed(text);
+    }
+
+    Cow::Owned(markdown(text))
+}
+
+pub fn markdown(text: &str) -> String {
+    let mut html = String::new();
+
+    html::push_html(&mut html, Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES));
+
+    html
+}
+
+pub fn gemtext(blocks: &[Block], resolve: impl Fn(&str) -> String) -> String {
+    render(blocks, |block| match block {
+        Block::Heading(level, text) => format!("{} {}", "#".repeat((*level).min(3) as usize), text),
+        Block::Paragraph(text) => text.clone(),
+        Block::Item(text) => format!("* {}", text),
+        Block::Quote(text) => text.lines().map(|line| format!("> {}", line)).collect::<Vec<_>>().join("\n"),
+        Block::Preformatted(text) => format!("```\n{}\n```", text),
+        Block::Link { url, text } => format!("=> {} {}", resolve(url), text),
+    })
+}
+
+// Plain text for clients that show it as is, so lines are wrapped here.
+pub fn plain(blocks: &[Block], width: usize, resolve: impl Fn(&str) -> String) -> String {
+    render(blocks, |block| match block {
+        Block::Heading(level, text) => {
+            let lines = wrap(text, width, "", "");
+            let length = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+            let underline = if *level == 1 { "=" } else { "-" };
+
+            format!("{}\n{}", lines.join("\n"), underline.repeat(length))
+        }
+        Block::Paragraph(text) => wrap(text, width, "", "").join("\n"),
+        Block::Item(text) => wrap(text, width, "* ", "  ").join("\n"),
+        Block::Quote(text) => wrap(text, width, "> ", "> ").join("\n"),
+        Block::Preformatted(text) => text.clone(),
+        Block::Link { url, text } => format!("{}: {}", text, resolve(url)),
+    })
+}
+
+fn render(blocks: &[Block], line: impl Fn(&Block) -> String) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut previous: Option<&Block> = None;
+
+    for block in blocks {
+        // Lists and runs of links stay together, everything else is spaced.
+        let together = matches!((previous, block),
+                                (Some(Block::Item(_)), Block::Item(_))
+                                | (Some(Block::Link { .. }), Block::Link { .. }));
+
+        if previous.is_some() && !together {
+            lines.push(String::new());
+        }
+
+        lines.push(line(block));
+        previous = Some(block);
+    }
+
+    lines.join("\n") + "\n"
+}
+
+// Words longer than the width are left whole rather than cut.
+fn wrap(text: &str, width: usize, first: &str, rest: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for paragraph in text.lines() {
+        let mut line = if lines.is_empty() { first.to_owned() } else { rest.to_owned() };
+        let mut length = line.chars().count();
+        let mut empty = true;
+
+        for word in paragraph.split_whitespace() {
+            let word_length = word.chars().count();
+
+            if !empty && length + 1 + word_length > width {
+                lines.push(std::mem::replace(&mut line, rest.to_owned()));
+                length = rest.chars().count();
+                empty = true;
+            }
+
+            if !empty {
+                line.push(' ');
+                length += 1;
+            }
+
+            line.push_str(word);
+            length += word_length;
+            empty = false;
+        }
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[derive(Default)]
+struct Sink {
+    blocks: Vec<Block>,
+    text: String,
+    links: Vec<(String, String)>,
+    link: Option<(String, String)>,
+    heading: Option<u8>,
+    items: usize,
+    quotes: usize,
+    pre: bool,
+    skipped: usize,
+    image: String,
+}
+
+const BREAKING_TAGS: &[&str] = &[
+    "p", "div", "section", "article", "header", "footer", "main", "aside", "nav",
+    "figure", "figcaption", "table", "tr", "ul", "ol", "dl", "dt", "dd", "hr",
+    "details", "summary",
+];
+
+fn attribute<'a>(tag: &'a Tag, name: &str) -> Option<&'a str> {
+    tag.attrs.iter()
+        .find(|attribute| &*attribute.name.local == name)
+        .map(|attribute| &*attribute.value)
+}
+
+fn push_collapsed(buffer: &mut String, text: &str) {
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !buffer.is_empty() && !buffer.ends_with(' ') && !buffer.ends_with('\n') {
+                buffer.push(' ');
+            }
+        } else {
+            buffer.push(c);
+        }
+    }
+}
+
+impl Sink {
+    fn flush(&mut self) {
+        let text = std::mem::take(&mut self.text);
+
+        let text = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if !text.is_empty() {
+            self.blocks.push(match self.heading {
+                Some(level) => Block::Heading(level, text.replace('\n', " ")),
+                None if self.items > 0 => Block::Item(text.replace('\n', " ")),
+                None if self.quotes > 0 => Block::Quote(text),
+                None => Block::Paragraph(text),
+            });
+        }
+
+        for (url, text) in self.links.drain(..) {
+            self.blocks.push(Block::Link { url, text });
+        }
+    }
+
+    fn start(&mut self, tag: &Tag) {
+        let name = &*tag.name;
+
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                self.heading = name[1..].parse().ok();
+            }
+            "li" => {
+                self.flush();
+                self.items += 1;
+            }
+            "blockquote" => {
+                self.flush();
+                self.quotes += 1;
+            }
+            "pre" => {
+                self.flush();
+                self.pre = true;
+            }
+            "br" => self.text.push('\n'),
+            "td" | "th" => self.text.push(' '),
+            "a" => {
+                self.link = attribute(tag, "href").map(|href| (href.to_owned(), String::new()));
+            }
+            "img" => {
+                if let Some(src) = attribute(tag, "src") {
+                    let alt = attribute(tag, "alt").map(str::trim).unwrap_or("");
+                    let alt = if alt.is_empty() { &self.image } else { alt };
+
+                    self.links.push((src.to_owned(), alt.to_owned()));
+                }
+            }
+            "script" | "style" => self.skipped += 1,
+            _ if BREAKING_TAGS.contains(&name) => self.flush(),
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: &Tag) {
+        let name = &*tag.name;
+
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                self.heading = None;
+            }
+            "li" => {
+                self.flush();
+                self.items = self.items.saturating_sub(1);
+            }
+            "blockquote" => {
+                self.flush();
+                self.quotes = self.quotes.saturating_sub(1);
+            }
+            "pre" => {
+                let text = std::mem::take(&mut self.text);
+                let text = text.trim_matches(|c| c == '\n' || c == '\r');
+
+                if !text.trim().is_empty() {
+                    self.blocks.push(Block::Preformatted(text.to_owned()));
+                }
+
+                self.pre = false;
+                self.flush();
+            }
+            "a" => {
+                if let Some((url, text)) = self.link.take() {
+                    let text = text.trim();
+                    let text = if text.is_empty() { url.clone() } else { text.to_owned() };
+
+                    self.links.push((url, text));
+                }
+            }
+            "script" | "style" => self.skipped = self.skipped.saturating_sub(1),
+            _ if BREAKING_TAGS.contains(&name) => self.flush(),
+            _ => {}
+        }
+    }
+
+    fn characters(&mut self, text: &str) {
+        if self.skipped > 0 {
+            return;
+        }
+
+        if self.pre {
+            self.text.push_str(text);
+        } else {
+            push_collapsed(&mut self.text, text);
+        }
+
+        if let Some((_, link_text)) = &mut self.link {
+            push_collapsed(link_text, text);
+        }
+    }
+}
+
+impl TokenSink for Sink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::TagToken(tag) => match tag.kind {
+                TagKind::StartTag => self.start(&tag),
+                TagKind::EndTag => self.end(&tag),
+            },
+            Token::CharacterTokens(text) => self.characters(&text),
+            _ => {}
+        }
+
+        TokenSinkResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ blocks, gemtext, plain, wrap };
+
+    fn resolve(url: &str) -> String {
+        match url.strip_prefix('/') {
+            Some(path) => format!("https://example.org/{}", path),
+            None => url.to_owned(),
+        }
+    }
+
+    #[test]
+    fn gemtext_blocks() {
+        let cases = [
+            ("<h1>Title</h1><h4>Deep</h4>", "# Title\n\n### Deep\n"),
+            ("<p>One\n   two</p><p>Three</p>", "One two\n\nThree\n"),
+            ("<p>See <a href=\"/articles/x\">this  one</a> and <a href=\"https://a.org\"></a>.</p>",
+             "See this one and .\n\n=> https://example.org/articles/x this one\n=> https://a.org https://a.org\n"),
+            ("<ul><li>First</li><li>Second <b>bold</b></li></ul><p>After</p>", "* First\n* Second bold\n\nAfter\n"),
+            ("<blockquote>Quoted<br>lines</blockquote>", "> Quoted\n> lines\n"),
+            ("<pre>\nfn main() {\n    x  y\n}\n</pre>", "```\nfn main() {\n    x  y\n}\n```\n"),
+            ("<p>Text<img src=\"/a.png\"><img src=\"/b.png\" alt=\" Cat \"></p>",
+             "Text\n\n=> https://example.org/a.png Image\n=> https://example.org/b.png Cat\n"),
+            ("<p>Shown<script>hidden()</script><style>p {}</style></p>", "Shown\n"),
+            ("# Markdown\n\n- item\n\n[link](/l)", "# Markdown\n\n* item\n\nlink\n\n=> https://example.org/l link\n"),
+        ];
+
+        for (html, expected) in &cases {
+            assert_eq!(gemtext(&blocks(html, "Image"), resolve), *expected, "{}", html);
+        }
+    }
+
+    #[test]
+    fn plain_blocks() {
+        let cases = [
+            ("<h1>Title</h1><h2>Section</h2>", "Title\n=====\n\nSection\n-------\n"),
+            ("<p>one two three four five</p>", "one two\nthree four\nfive\n"),
+            ("<ul><li>one two three</li></ul>", "* one two\n  three\n"),
+            ("<blockquote>one two three</blockquote>", "> one two\n> three\n"),
+            ("<pre>  one two three four  </pre>", "  one two three four  \n"),
+            ("<p><a href=\"/x\">Link</a></p>", "Link\n\nLink: https://example.org/x\n"),
+        ];
+
+        for (html, expected) in &cases {
+            assert_eq!(plain(&blocks(html, "Image"), 10, resolve), *expected, "{}", html);
+        }
+    }
+
+    #[test]
+    fn wrap_counts_characters() {
+        let cases: [(&str, usize, &str, &[&str]); 5] = [
+            ("", 10, "", &[]),
+            ("один два три", 8, "", &["один два", "три"]),
+            ("достопримечательность рядом", 10, "", &["достопримечательность", "рядом"]),
+            ("а достопримечательность б", 10, "", &["а", "достопримечательность", "б"]),
+            ("первая\nвторая строка", 12, "* ", &["* первая", "  вторая", "  строка"]),
+        ];
+
+        for (text, width, first, expected) in &cases {
+            assert_eq!(wrap(text, *width, first, &" ".repeat(first.len())), *expected, "{}", text);
+        }
+    }
+}
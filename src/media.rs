@@ -0,0 +1,271 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io::Cursor;
+use std::error::Error;
+use std::path::Path;
+use actix_multipart::Multipart;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use futures::{ StreamExt, TryStreamExt };
+use openssl::sha::sha256;
+use rusqlite::{ params, Row, OptionalExtension };
+use serde::Serialize;
+
+use crate::errors::*;
+use crate::state::State;
+use crate::compression::serve_static;
+use crate::post::PostDate;
+
+#[derive(Serialize)]
+pub struct Media {
+    pub id: i64,
+    pub file: String,
+    pub url: String,
+    pub name: String,
+    pub mime: String,
+    pub size: i64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub alt: String,
+    pub date: Option<PostDate>,
+}
+
+impl Media {
+    fn from_row(row: &Row) -> Result<Media, Box<dyn Error>> {
+        let file: String = row.get(1)?;
+
+        Ok(Media {
+            id: row.get(0)?,
+            url: format!("/media/files/{}", file),
+            file,
+            name: row.get(2)?,
+            mime: row.get(3)?,
+            size: row.get(4)?,
+            width: row.get(5)?,
+            height: row.get(6)?,
+            alt: row.get(7)?,
+            date: PostDate::from_timestamp(row.get(8)?),
+        })
+    }
+}
+
+// Types are recognized by their contents only, what the browser claims is
+// ignored. Anything that could run scripts, like SVG or HTML, is refused.
+fn sniff(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    let starts = |magic: &[u8]| data.starts_with(magic);
+
+    if starts(b"\x89PNG\r\n\x1a\n") {
+        Some(("image/png", "png"))
+    } else if starts(b"\xff\xd8\xff") {
+        Some(("image/jpeg", "jpg"))
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        Some(("image/gif", "gif"))
+    } else if starts(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        Some(("image/webp", "webp"))
+    } else if data.get(4..12) == Some(b"ftypavif") {
+        Some(("image/avif", "avif"))
+    } else if data.get(4..8) == Some(b"ftyp") {
+        Some(("video/mp4", "mp4"))
+    } else if starts(b"\x1a\x45\xdf\xa3") {
+        Some(("video/webm", "webm"))
+    } else if starts(b"OggS") {
+        Some(("audio/ogg", "ogg"))
+    } else if starts(b"ID3") || starts(b"\xff\xfb") {
+        Some(("audio/mpeg", "mp3"))
+    } else if starts(b"%PDF-") {
+        Some(("application/pdf", "pdf"))
+    } else {
+        None
+    }
+}
+
+pub async fn media(req: HttpRequest,
+                   state: web::Data<State<'_>>) -> HttpResponse {
+    try_500!(media_inner(req, state).await, state, req)
+}
+
+pub async fn media_upload(req: HttpRequest,
+                          state: web::Data<State<'_>>,
+                          payload: Multipart) -> HttpResponse {
+    try_500!(media_upload_inner(req, state, payload).await, state, req)
+}
+
+pub async fn media_delete(req: HttpRequest,
+                          state: web::Data<State<'_>>,
+                          id: web::Path<i64>) -> HttpResponse {
+    try_500!(media_delete_inner(req, state, id).await, state, req)
+}
+
+pub async fn media_file(req: HttpRequest,
+                        state: web::Data<State<'_>>,
+                        file: web::Path<String>) -> HttpResponse {
+    match serve_static(&req, Path::new(&state.config.media.dir), &file) {
+        Some(response) => try_500!(response, state, req),
+        None => error_404(req, state).await,
+    }
+}
+
+async fn media_inner(req: HttpRequest,
+                     state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    let mut context = state.context(&req)?;
+
+    let mut stmt = state.conn.prepare("
+        SELECT *
+        FROM
+            media
+        ORDER BY
+            date DESC
+    ")?;
+
+    let mut rows = stmt.query([])?;
+    let mut items: Vec<Media> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(Media::from_row(row)?);
+    }
+
+    context.insert("media", &items);
+    context.insert("max_size", &state.config.media.max_size);
+
+    Ok(HttpResponse::Ok().body(state.tera.render("media.html", &context)?))
+}
+
+async fn media_upload_inner(req: HttpRequest,
+                            state: web::Data<State<'_>>,
+                            mut payload: Multipart) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    let max_size = state.config.media.max_size;
+    let mut data: Vec<u8> = Vec::new();
+    let mut name = String::new();
+    let mut alt: Vec<u8> = Vec::new();
+
+    while let Some(mut field) = payload.try_next().await? {
+        let disposition = field.content_disposition().ok_or("Field without disposition")?;
+
+        match disposition.get_name() {
+            Some("file") => {
+                name = disposition.get_filename().unwrap_or_default().to_owned();
+
+                while let Some(chunk) = field.next().await {
+                    let chunk = chunk?;
+
+                    if data.len() + chunk.len() > max_size {
+                        return Ok(HttpResponse::PayloadTooLarge()
+                            .body(format!("File is larger than {} bytes", max_size)));
+                    }
+
+                    data.extend_from_slice(&chunk);
+                }
+            }
+
+            Some("alt") => {
+                while let Some(chunk) = field.next().await {
+                    let chunk = chunk?;
+
+                    if alt.len() + chunk.len() > 1024 {
+                        return Ok(HttpResponse::BadRequest().body("Alt text is too long"));
+                    }
+
+                    alt.extend_from_slice(&chunk);
+                }
+            }
+
+            _ => while field.next().await.is_some() {},
+        }
+    }
+
+    // Chunks can split a character, so the text is decoded whole.
+    let alt = match String::from_utf8(alt) {
+        Ok(alt) => alt,
+        Err(_) => return Ok(HttpResponse::BadRequest().body("Alt text is not UTF-8")),
+    };
+
+    let (mime, extension) = match sniff(&data) {
+        Some(kind) => kind,
+        None => return Ok(HttpResponse::UnsupportedMediaType().body("Unsupported file type")),
+    };
+
+    let hash: String = sha256(&data)[..12].iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let file = format!("{}.{}", hash, extension);
+
+    let (width, height) = match image::ImageReader::new(Cursor::new(&data))
+        .with_guessed_format()?
+        .into_dimensions()
+    {
+        Ok((width, height)) => (Some(width), Some(height)),
+        Err(_) => (None, None),
+    };
+
+    let dir = Path::new(&state.config.media.dir);
+
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(&file), &data)?;
+
+    state.conn.execute("
+        INSERT INTO media (file, name, mime, size, width, height, alt, date)
+        VALUES (?, ?, ?, ?, ?, ?, ?, strftime('%s', 'now'))
+        ON CONFLICT(file) DO UPDATE SET
+            name=excluded.name,
+            alt=excluded.alt
+    ", params![file, name, mime, data.len() as i64, width, height, alt.trim()])?;
+
+    Ok(HttpResponse::SeeOther()
+        .header("Location", "/media")
+        .finish())
+}
+
+async fn media_delete_inner(req: HttpRequest,
+                            state: web::Data<State<'_>>,
+                            id: web::Path<i64>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    let file: Option<String> = state.conn.query_row("
+        SELECT
+            file
+        FROM
+            media
+        WHERE
+            id=?
+    ", params![*id], |row| row.get(0)).optional()?;
+
+    if let Some(file) = file {
+        state.conn.execute("DELETE FROM media WHERE id=?", params![*id])?;
+
+        let path = Path::new(&state.config.media.dir).join(file);
+
+        if let Err(e) = fs::remove_file(&path) {
+            eprintln!("Media file {} removing failed: {}", path.display(), e);
+        }
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .header("Location", "/media")
+        .finish())
+}
@@ -0,0 +1,490 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::sync::Arc;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::error::BlockingError;
+use chrono::Utc;
+use lettre::Address;
+use lettre::message::Mailbox;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use rusqlite::{ params, Connection };
+use serde::Deserialize;
+
+use crate::assets::encode_path;
+use crate::config::Config;
+use crate::errors::*;
+use crate::i18n::{ self, Catalog };
+use crate::mail::{ Letter, Mailer };
+use crate::publish::{ Change, Event, Listener };
+use crate::spam::{ self, Protection };
+use crate::state::State;
+
+const CONFIRM_TIME: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct SubscribeFormData {
+    email: String,
+    #[serde(flatten)]
+    protection: Protection,
+}
+
+#[derive(Deserialize)]
+pub struct LinkQuery {
+    email: String,
+    #[serde(default)]
+    expires: i64,
+    signature: String,
+}
+
+// Links in letters have to survive restarts, so they are signed with a key
+// derived from the auth token. Changing the token invalidates them.
+fn signature(config: &Config, action: &str, email: &str, expires: i64) -> Result<String, Box<dyn Error>> {
+    let key = PKey::hmac(&sha256(format!("newsletter:{}", config.token).as_bytes()))?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+
+    signer.update(format!("{}:{}:{}", action, email.to_lowercase(), expires).as_bytes())?;
+
+    Ok(signer.sign_to_vec()?.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn verify(config: &Config, action: &str, query: &LinkQuery) -> Result<bool, Box<dyn Error>> {
+    let expected = signature(config, action, &query.email, query.expires)?;
+
+    if expected.len() != query.signature.len()
+        || !memcmp::eq(expected.as_bytes(), query.signature.as_bytes())
+    {
+        return Ok(false);
+    }
+
+    Ok(query.expires == 0 || query.expires >= Utc::now().timestamp())
+}
+
+fn link(config: &Config, action: &str, email: &str, expires: i64) -> Result<String, Box<dyn Error>> {
+    Ok(format!("https://{}/newsletter/{}?email={}&expires={}&signature={}",
+               config.host,
+               action,
+               encode_path(email),
+               expires,
+               signature(config, action, email, expires)?))
+}
+
+pub fn unsubscribe_link(config: &Config, email: &str) -> Result<String, Box<dyn Error>> {
+    link(config, "unsubscribe", email, 0)
+}
+
+pub async fn newsletter(req: HttpRequest,
+                        state: web::Data<State<'_>>) -> HttpResponse {
+    let mut context = try_500!(state.context(&req), state, req);
+
+    context.insert("status", "form");
+
+    HttpResponse::Ok().body(try_500!(state.tera.render("newsletter.html", &context), state, req))
+}
+
+pub async fn subscribe(req: HttpRequest,
+                       state: web::Data<State<'_>>,
+                       form: web::Form<SubscribeFormData>) -> HttpResponse {
+    try_500!(subscribe_inner(req, state, form).await, state, req)
+}
+
+pub async fn confirm(req: HttpRequest,
+                     state: web::Data<State<'_>>,
+                     query: web::Query<LinkQuery>) -> HttpResponse {
+    try_500!(confirm_inner(req, state, query).await, state, req)
+}
+
+pub async fn unsubscribe_form(req: HttpRequest,
+                              state: web::Data<State<'_>>,
+                              query: web::Query<LinkQuery>) -> HttpResponse {
+    try_500!(unsubscribe_form_inner(req, state, query).await, state, req)
+}
+
+pub async fn unsubscribe(req: HttpRequest,
+                         state: web::Data<State<'_>>,
+                         query: web::Query<LinkQuery>) -> HttpResponse {
+    try_500!(unsubscribe_inner(req, state, query).await, state, req)
+}
+
+fn render(req: &HttpRequest,
+          state: &State<'_>,
+          status: &str) -> Result<HttpResponse, Box<dyn Error>> {
+    let mut context = state.context(req)?;
+
+    context.insert("status", status);
+
+    let body = state.tera.render("newsletter.html", &context)?;
+
+    Ok(match status {
+        "invalid" => HttpResponse::BadRequest().body(body),
+        _ => HttpResponse::Ok().body(body),
+    })
+}
+
+async fn subscribe_inner(req: HttpRequest,
+                         state: web::Data<State<'_>>,
+                         form: web::Form<SubscribeFormData>) -> Result<HttpResponse, Box<dyn Error>> {
+    let checked = match state.form_guard.check(&form.protection, &[]) {
+        Ok(checked) => checked,
+        Err(rejection) => return spam::rejected(&req, &state, rejection),
+    };
+
+    let mailer = match state.mailer.clone() {
+        Some(mailer) => mailer,
+        None => return Ok(error_404(req.clone(), state.clone()).await),
+    };
+
+    let address = match form.email.trim().parse::<Address>() {
+        Ok(address) => address,
+        Err(_) => return render(&req, &state, "invalid"),
+    };
+
+    if let Err(rejection) = state.form_guard.consume(checked) {
+        return spam::rejected(&req, &state, rejection);
+    }
+
+    let email = address.to_string();
+    let lang = state.lang(&req);
+
+    state.conn.execute("
+        INSERT INTO subscribers (email, lang, confirmed, date)
+        VALUES (?, ?, 0, strftime('%s', 'now'))
+        ON CONFLICT(email) DO NOTHING
+    ", params![email, lang])?;
+
+    let confirmed: bool = state.conn.query_row("
+        SELECT
+            confirmed
+        FROM
+            subscribers
+        WHERE
+            email=?
+    ", params![email], |row| row.get(0))?;
+
+    // The answer is the same either way, so the form does not tell who is
+    // subscribed already.
+    if !confirmed {
+        let mut args = i18n::arg("host", state.config.host.as_str());
+
+        args.set("link", link(&state.config, "confirm", &email, Utc::now().timestamp() + CONFIRM_TIME)?);
+
+        let letter = Letter {
+            to: Mailbox::new(None, address),
+            reply_to: None,
+            subject: state.catalog.translate(&lang, "subscription-mail-subject", None),
+            body: format!("{}\n", state.catalog.translate(&lang, "subscription-mail-body", Some(&args))),
+            unsubscribe: None,
+        };
+
+        match web::block(move || mailer.send(letter).map_err(|e| e.to_string())).await {
+            Ok(()) => {}
+            Err(BlockingError::Error(e)) => return Err(e.into()),
+            Err(BlockingError::Canceled) => return Err("Mail sending was canceled".into()),
+        }
+    }
+
+    render(&req, &state, "sent")
+}
+
+async fn confirm_inner(req: HttpRequest,
+                       state: web::Data<State<'_>>,
+                       query: web::Query<LinkQuery>) -> Result<HttpResponse, Box<dyn Error>> {
+    if query.expires == 0 || !verify(&state.config, "confirm", &query)? {
+        return render(&req, &state, "invalid");
+    }
+
+    state.conn.execute("UPDATE subscribers SET confirmed=1 WHERE email=?", params![query.email])?;
+
+    render(&req, &state, "confirmed")
+}
+
+// Mail scanners and prefetchers open links, so following the link only asks,
+// and the unsubscription itself is a POST, either from the page or from the
+// mail client through List-Unsubscribe-Post of RFC 8058.
+async fn unsubscribe_form_inner(req: HttpRequest,
+                                state: web::Data<State<'_>>,
+                                query: web::Query<LinkQuery>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !verify(&state.config, "unsubscribe", &query)? {
+        return render(&req, &state, "invalid");
+    }
+
+    let mut context = state.context(&req)?;
+
+    context.insert("status", "unsubscribe");
+    context.insert("email", &query.email);
+    context.insert("query", req.query_string());
+
+    Ok(HttpResponse::Ok().body(state.tera.render("newsletter.html", &context)?))
+}
+
+async fn unsubscribe_inner(req: HttpRequest,
+                           state: web::Data<State<'_>>,
+                           query: web::Query<LinkQuery>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !verify(&state.config, "unsubscribe", &query)? {
+        return render(&req, &state, "invalid");
+    }
+
+    state.conn.execute("DELETE FROM subscribers WHERE email=?", params![query.email])?;
+
+    render(&req, &state, "unsubscribed")
+}
+
+// Mails every confirmed subscriber a list of the articles published since
+// the last poll.
+pub struct Digest {
+    pub config: Arc<Config>,
+    pub catalog: Arc<Catalog>,
+    pub mailer: Arc<dyn Mailer>,
+}
+
+impl Listener for Digest {
+    fn name(&self) -> &'static str {
+        "newsletter"
+    }
+
+    fn notify(&self, conn: &Connection, events: &[Event]) -> Result<(), Box<dyn Error>> {
+        let published: Vec<String> = events.iter()
+            .filter(|event| event.change == Change::Published)
+            .map(|event| format!("{}\nhttps://{}/articles/{}\n",
+                                 event.post.name,
+                                 self.config.host,
+                                 encode_path(&event.post.link)))
+            .collect();
+
+        if published.is_empty() {
+            return Ok(());
+        }
+
+        let mut stmt = conn.prepare("
+            SELECT
+                email, lang
+            FROM
+                subscribers
+            WHERE
+                confirmed=1
+        ")?;
+
+        let subscribers: Vec<(String, Option<String>)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        let host = i18n::arg("host", self.config.host.as_str());
+
+        for (email, lang) in subscribers {
+            // Those who subscribed before the locale was kept get the default one.
+            let lang = lang.as_deref().unwrap_or_else(|| self.catalog.default_locale());
+
+            // One bad address must not keep the others from their letters.
+            let to = match email.parse() {
+                Ok(to) => to,
+                Err(e) => {
+                    eprintln!("Newsletter skips invalid address {}: {}", email, e);
+                    continue;
+                }
+            };
+
+            let unsubscribe = unsubscribe_link(&self.config, &email)?;
+            let footer = self.catalog.translate(lang, "digest-unsubscribe",
+                                                Some(&i18n::arg("link", unsubscribe.as_str())));
+
+            let letter = Letter {
+                to,
+                reply_to: None,
+                subject: self.catalog.translate(lang, "digest-mail-subject", Some(&host)),
+                body: format!("{}\n--\n{}\n", published.join("\n"), footer),
+                unsubscribe: Some(unsubscribe),
+            };
+
+            if let Err(e) = self.mailer.send(letter) {
+                eprintln!("Newsletter sending to {} failed: {}", email, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use chrono::Utc;
+    use rusqlite::{ params, Connection };
+    use serde_json::json;
+
+    use crate::config::{ Config, I18n };
+    use crate::i18n::Catalog;
+    use crate::mail;
+    use crate::post::Post;
+    use crate::publish::{ Change, Event, Listener };
+    use crate::schema;
+    use super::{ link, signature, unsubscribe_link, verify, Digest, LinkQuery, CONFIRM_TIME };
+
+    fn config(token: &str) -> Config {
+        serde_json::from_value(json!({
+            "host": "example.org",
+            "database": "",
+            "templates": "",
+            "token": token,
+            "geoip_db_file": "",
+        })).unwrap()
+    }
+
+    fn query(config: &Config, action: &str, email: &str, expires: i64) -> LinkQuery {
+        LinkQuery {
+            email: email.to_owned(),
+            expires,
+            signature: signature(config, action, email, expires).unwrap(),
+        }
+    }
+
+    #[test]
+    fn confirm_signature() {
+        let other = config("other");
+        let config = config("secret");
+        let expires = Utc::now().timestamp() + CONFIRM_TIME;
+        let query = query(&config, "confirm", "reader@example.net", expires);
+
+        assert!(verify(&config, "confirm", &query).unwrap());
+        assert!(!verify(&config, "unsubscribe", &query).unwrap());
+        assert!(!verify(&other, "confirm", &query).unwrap());
+
+        assert_eq!(link(&config, "confirm", "reader@example.net", expires).unwrap(),
+                   format!("https://example.org/newsletter/confirm?email=reader%40example.net&expires={}&signature={}",
+                           expires, query.signature));
+    }
+
+    #[test]
+    fn confirm_expires() {
+        let config = config("secret");
+        let now = Utc::now().timestamp();
+
+        assert!(verify(&config, "confirm", &query(&config, "confirm", "reader@example.net", now + CONFIRM_TIME)).unwrap());
+        assert!(!verify(&config, "confirm", &query(&config, "confirm", "reader@example.net", now - 1)).unwrap());
+        assert!(!verify(&config, "confirm", &query(&config, "confirm", "reader@example.net", now - CONFIRM_TIME)).unwrap());
+    }
+
+    #[test]
+    fn tampered_link() {
+        let config = config("secret");
+        let expires = Utc::now().timestamp() + CONFIRM_TIME;
+        let original = query(&config, "confirm", "reader@example.net", expires);
+
+        let other_email = LinkQuery { email: "other@example.net".to_owned(), ..query(&config, "confirm", "reader@example.net", expires) };
+        let later = LinkQuery { expires: expires + CONFIRM_TIME, ..query(&config, "confirm", "reader@example.net", expires) };
+        let short = LinkQuery { signature: original.signature[1..].to_owned(), ..query(&config, "confirm", "reader@example.net", expires) };
+
+        let mut flipped = original.signature.clone().into_bytes();
+
+        flipped[0] = if flipped[0] == b'0' { b'1' } else { b'0' };
+
+        let flipped = LinkQuery { signature: String::from_utf8(flipped).unwrap(), ..original };
+
+        for query in &[other_email, later, short, flipped] {
+            assert!(!verify(&config, "confirm", query).unwrap());
+        }
+    }
+
+    #[test]
+    fn unsubscribe_never_expires() {
+        let config = config("secret");
+        let query = query(&config, "unsubscribe", "Reader@Example.net", 0);
+
+        assert!(verify(&config, "unsubscribe", &query).unwrap());
+        assert!(unsubscribe_link(&config, "Reader@Example.net").unwrap()
+            .ends_with(&format!("&expires=0&signature={}", query.signature)));
+
+        // Addresses are compared without case, as mail servers do.
+        assert_eq!(signature(&config, "unsubscribe", "reader@example.net", 0).unwrap(), query.signature);
+    }
+
+    fn event(change: Change, link: &str, name: &str) -> Event {
+        Event {
+            change,
+            post: Post {
+                link: link.to_owned(),
+                name: name.to_owned(),
+                text: String::new(),
+                short_text: None,
+                date: None,
+                lastmod: None,
+            },
+        }
+    }
+
+    #[test]
+    fn digest_through_file_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let mail_config: crate::config::Mail = serde_json::from_value(json!({
+            "transport": "file",
+            "dir": dir.path(),
+            "from": "blog@example.org",
+            "to": "blog@example.org",
+        })).unwrap();
+
+        let digest = Digest {
+            config: Arc::new(config("secret")),
+            catalog: Arc::new(Catalog::load(&I18n::default()).unwrap()),
+            mailer: mail::mailer(&mail_config).unwrap(),
+        };
+
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        schema::migrate(&mut conn).unwrap();
+
+        for (email, lang, confirmed) in &[("reader@example.net", Some("en"), true),
+                                          ("pending@example.net", Some("en"), false),
+                                          ("old@example.net", None, true)] {
+            conn.execute("INSERT INTO subscribers (email, lang, confirmed, date) VALUES (?, ?, ?, 0)",
+                         params![email, lang, confirmed]).unwrap();
+        }
+
+        // Updates alone are not news.
+        digest.notify(&conn, &[event(Change::Updated, "old", "Old article")]).unwrap();
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        digest.notify(&conn, &[event(Change::Published, "new", "New article"),
+                               event(Change::Updated, "old", "Old article")]).unwrap();
+
+        let letters: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+
+        assert_eq!(letters.len(), 2);
+        assert!(!letters.iter().any(|letter| letter.contains("pending@example.net")));
+
+        let letter = letters.iter().find(|letter| letter.contains("To: reader@example.net")).unwrap();
+
+        assert!(letter.contains("Subject: New on example.org"));
+        assert!(letter.contains("New article\r\nhttps://example.org/articles/new"));
+        assert!(!letter.contains("Old article"));
+        assert!(letter.contains(&format!("List-Unsubscribe: <{}>",
+                                         unsubscribe_link(&digest.config, "reader@example.net").unwrap())));
+        assert!(letter.contains("Unsubscribe: https://example.org/newsletter/unsubscribe?email="));
+        assert!(letter.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+
+        // The one subscribed before the locale was kept gets the default one.
+        assert!(letters.iter().any(|letter| letter.contains("To: old@example.net")
+                                   && !letter.contains("Subject: New on")));
+    }
+}
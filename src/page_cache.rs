@@ -0,0 +1,198 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use actix_web::{ web, web::Bytes, HttpRequest, HttpResponse };
+use actix_web::http::header::EntityTag;
+use lru::LruCache;
+use serde::Serialize;
+use tera::Context;
+
+use crate::errors::*;
+use crate::state::State;
+use crate::post::PostDate;
+use crate::{ caching, i18n, security };
+
+#[derive(Hash, PartialEq, Eq)]
+pub struct PageKey {
+    pub path: String,
+    pub variant: String,
+}
+
+// Pages are rendered with a placeholder instead of the CSP nonce, which is
+// replaced by the nonce of each request when the page is sent.
+const NONCE_PLACEHOLDER: &str = "cspnonceplaceholder";
+
+pub struct CachedPage {
+    pub body: Bytes,
+    pub etag: EntityTag,
+    pub modified: Option<PostDate>,
+}
+
+#[derive(Serialize)]
+pub struct PageCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub struct PageCache {
+    pages: Mutex<LruCache<PageKey, Arc<CachedPage>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache {
+            pages: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &PageKey) -> Option<Arc<CachedPage>> {
+        let page = self.pages.lock().ok()?.get(key).cloned();
+
+        match page {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        page
+    }
+
+    pub fn insert(&self, key: PageKey, page: CachedPage) -> Arc<CachedPage> {
+        let page = Arc::new(page);
+
+        if let Ok(mut pages) = self.pages.lock() {
+            if pages.cap() > 0 {
+                pages.put(key, page.clone());
+            }
+        }
+
+        page
+    }
+
+    pub fn invalidate(&self) {
+        if let Ok(mut pages) = self.pages.lock() {
+            pages.clear();
+        }
+    }
+
+    pub fn stats(&self) -> PageCacheStats {
+        let (entries, capacity) = match self.pages.lock() {
+            Ok(pages) => (pages.len(), pages.cap()),
+            Err(_) => (0, 0),
+        };
+
+        PageCacheStats {
+            entries,
+            capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl PageKey {
+    pub fn from_request(req: &HttpRequest, state: &State) -> PageKey {
+        let mut variant = i18n::locale(req).map(|locale| locale.variant()).unwrap_or_default();
+
+        if state.config.dates.reader_timezone {
+            variant.push(' ');
+            variant.push_str(state.timezone(req).name());
+        }
+
+        PageKey { path: req.path().to_owned(), variant }
+    }
+}
+
+// Only anonymous responses are cached, since authorized ones differ in
+// header links and must not leak to other readers.
+pub fn cached_response(req: &HttpRequest,
+                       state: &State) -> Result<Option<HttpResponse>, Box<dyn Error>> {
+    if state.authorized(req)? {
+        return Ok(None);
+    }
+
+    state.check_data_version()?;
+
+    Ok(state.page_cache.get(&PageKey::from_request(req, state))
+        .map(|page| page_response(req, state, &page, false)))
+}
+
+pub fn render(req: &HttpRequest,
+              state: &State,
+              template: &str,
+              context: &Context,
+              modified: Option<&PostDate>) -> Result<HttpResponse, Box<dyn Error>> {
+    let authorized = state.authorized(req)?;
+
+    let mut context = context.clone();
+    context.insert("csp_nonce", NONCE_PLACEHOLDER);
+
+    let body = state.tera.render(template, &context)?;
+
+    let page = CachedPage {
+        etag: caching::etag(body.as_bytes()),
+        body: body.into(),
+        modified: modified.cloned(),
+    };
+
+    let page = if authorized {
+        Arc::new(page)
+    } else {
+        state.page_cache.insert(PageKey::from_request(req, state), page)
+    };
+
+    Ok(page_response(req, state, &page, authorized))
+}
+
+fn page_response(req: &HttpRequest,
+                 state: &State,
+                 page: &CachedPage,
+                 authorized: bool) -> HttpResponse {
+    let body = match std::str::from_utf8(&page.body) {
+        Ok(body) if body.contains(NONCE_PLACEHOLDER) => {
+            let nonce = security::nonce(req).unwrap_or_default();
+            Bytes::from(body.replace(NONCE_PLACEHOLDER, &nonce))
+        }
+
+        _ => page.body.clone(),
+    };
+
+    caching::respond(req, body, &page.etag, page.modified.as_ref(), authorized,
+                     state.config.cache_max_age)
+}
+
+pub async fn page_cache_stats(req: HttpRequest,
+                              state: web::Data<State<'_>>) -> HttpResponse {
+    try_500!(page_cache_stats_inner(req, state).await, state, req)
+}
+
+async fn page_cache_stats_inner(req: HttpRequest,
+                                state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
+    if !state.authorized(&req)? {
+        return Ok(error_404(req.clone(), state.clone()).await);
+    }
+
+    Ok(HttpResponse::Ok().json(state.page_cache.stats()))
+}
@@ -16,19 +16,27 @@
  */
 
 use std::error::Error;
+use std::net::{ IpAddr, SocketAddr };
 
 use actix_web::{ web, Responder, HttpResponse, HttpRequest };
-use tera::Context;
-use rusqlite::params;
+use geoip2::{ Country, Reader };
+use rusqlite::{ params, Connection };
 
 use crate::errors::*;
 use crate::state::State;
 use crate::post::Post;
+use crate::i18n::{ Alternate, Catalog };
+use crate::{ activitypub, caching, comments, page_cache, webmention };
+
+pub struct Translation {
+    pub lang: String,
+    pub link: String,
+}
 
 pub async fn article_redirect(link: web::Path<String>) -> impl Responder {
-    return HttpResponse::PermanentRedirect()
+    HttpResponse::PermanentRedirect()
         .header("Location", format!("/articles/{}", link))
-        .finish();
+        .finish()
 }
 
 pub async fn article_index(req: HttpRequest,
@@ -79,45 +87,61 @@ pub async fn index(req: HttpRequest, state: web::Data<State<'_>>) -> impl Respon
 async fn article_index_inner(req: HttpRequest,
                              state: web::Data<State<'_>>,
                              link: web::Path<String>) -> Result<HttpResponse, Box<dyn Error>> {
-    let mut context = Context::new();
-
     fail_russia(&req, state.clone())?;
 
-    let auth = state.auth.read().map_err(MyError::from)?;
+    // Fediverse servers ask for the same URL, but want the ActivityPub object.
+    let activity = activitypub::wants_activity(&req);
 
-    context.insert("authorized", &auth.authorized(&req));
+    if !activity {
+        if let Some(response) = page_cache::cached_response(&req, &state)? {
+            return Ok(activitypub::vary_accept(response));
+        }
+    }
 
-    let mut stmt = state.conn.prepare("
-        SELECT *
-        FROM
-            articles
-        WHERE
-            link=?
-    ")?;
+    let post = match find_article(&state.conn, &link)? {
+        Some(post) => post,
+        None => return Ok(error_404(req.clone(), state.clone()).await),
+    };
 
-    let mut rows = stmt.query(params![link.to_string()])?;
+    if activity {
+        if let Some(response) = activitypub::article_object(&state, &post) {
+            return Ok(response);
+        }
+    }
 
-    let post = if let Some(row) = rows.next()? {
-        Post::from_row(row)?
-    } else {
-        return Ok(error_404(req.clone(), state.clone()).await);
-    };
+    let mut context = state.context(&req)?;
+
+    let translations = translations(&state.conn, &link)?;
+
+    if translations.len() > 1 {
+        context.insert("alternates", &translation_alternates(&state.catalog, &state.config.host, &translations));
+    }
+
+    let comments = comments::thread(&state.conn, &link)?;
+    let webmentions = webmention::for_article(&state.conn, &link)?;
+
+    // The page changes with every new comment and mention as well.
+    let modified = caching::latest(caching::modified(&post).into_iter()
+        .chain(comments.iter().filter_map(|comment| comment.date.as_ref()))
+        .chain(webmentions.iter().filter_map(|mention| mention.date.as_ref())));
 
     context.insert("post", &post);
+    context.insert("comments", &comments);
+    context.insert("webmentions", &webmentions);
 
-    Ok(HttpResponse::Ok().body(state.tera.render("post.html", &context)?))
+    page_cache::render(&req, &state, "post.html", &context, modified).map(activitypub::vary_accept)
 }
 
 async fn hidden_article_index_inner(req: HttpRequest,
                                     state: web::Data<State<'_>>,
                                     link: web::Path<String>) -> Result<HttpResponse, Box<dyn Error>> {
-    let mut context = Context::new();
-
     fail_russia(&req, state.clone())?;
 
-    let auth = state.auth.read().map_err(MyError::from)?;
+    if let Some(response) = page_cache::cached_response(&req, &state)? {
+        return Ok(response);
+    }
 
-    context.insert("authorized", &auth.authorized(&req));
+    let mut context = state.context(&req)?;
 
     let mut stmt = state.conn.prepare("
         SELECT *
@@ -137,20 +161,29 @@ async fn hidden_article_index_inner(req: HttpRequest,
 
     context.insert("post", &post);
 
-    Ok(HttpResponse::Ok().body(state.tera.render("post.html", &context)?))
+    page_cache::render(&req, &state, "post.html", &context, caching::modified(&post))
 }
 
 async fn articles_inner(req: HttpRequest,
                         state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
-    let mut context = Context::new();
-
     fail_russia(&req, state.clone())?;
 
-    let auth = state.auth.read().map_err(MyError::from)?;
+    if let Some(response) = page_cache::cached_response(&req, &state)? {
+        return Ok(response);
+    }
 
-    context.insert("authorized", &auth.authorized(&req));
+    let mut context = state.context(&req)?;
+    let posts = listed_articles(&state.conn, &state.lang(&req))?;
 
-    let mut stmt = state.conn.prepare("
+    context.insert("posts", &posts);
+
+    page_cache::render(&req, &state, "posts.html", &context, caching::newest(&posts))
+}
+
+// The other mirrors of the site list and show articles with these too, so
+// they all agree on what is published.
+pub fn published_articles(conn: &Connection) -> Result<Vec<Post>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("
         SELECT *
         FROM
             articles
@@ -167,38 +200,129 @@ async fn articles_inner(req: HttpRequest,
         posts.push(Post::from_row(row)?);
     }
 
-    context.insert("posts", &posts);
+    Ok(posts)
+}
 
-    Ok(HttpResponse::Ok().body(state.tera.render("posts.html", &context)?))
+// The list in a language leaves out the articles translated into it, as
+// the translations are listed instead.
+fn listed_articles(conn: &Connection, lang: &str) -> Result<Vec<Post>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("
+        SELECT articles.*
+        FROM
+            articles
+            LEFT JOIN translations ON translations.link=articles.link AND translations.hidden=0
+        WHERE
+            dnshow=0 AND (
+                translations.lang IS NULL OR translations.lang=?1 OR NOT EXISTS (
+                    SELECT *
+                    FROM
+                        translations AS other
+                        JOIN articles AS translated ON translated.link=other.link
+                    WHERE
+                        other.hidden=0 AND other.key=translations.key AND other.lang=?1
+                        AND translated.dnshow=0
+                )
+            )
+        ORDER BY
+            date DESC
+    ")?;
+
+    let mut rows = stmt.query(params![lang])?;
+    let mut posts: Vec<Post> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        posts.push(Post::from_row(row)?);
+    }
+
+    Ok(posts)
 }
 
-fn fail_russia(req: &HttpRequest,
-               state: web::Data<State<'_>>) -> Result<(), MyError> {
-    let country_code = get_peer_country(req, state);
+// Articles sharing the translation key of this one, itself included.
+pub fn translations(conn: &Connection, link: &str) -> Result<Vec<Translation>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("
+        SELECT
+            other.lang,
+            other.link
+        FROM
+            translations AS this
+            JOIN translations AS other ON other.key=this.key AND other.hidden=0
+            JOIN articles ON articles.link=other.link
+        WHERE
+            this.link=? AND this.hidden=0
+        ORDER BY
+            other.lang
+    ")?;
 
-    if let Some(country_code) = country_code {
-        if country_code == "RU" {
-            return Err(MyError::new_russia());
-        }
+    let translations = stmt
+        .query_map(params![link], |row| Ok(Translation { lang: row.get(0)?, link: row.get(1)? }))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(translations)
+}
+
+// The translation in the default locale is also shown without a prefix.
+pub fn translation_alternates(catalog: &Catalog,
+                              host: &str,
+                              translations: &[Translation]) -> Vec<Alternate> {
+    let mut alternates: Vec<Alternate> = translations.iter()
+        .map(|translation| Alternate {
+            lang: translation.lang.clone(),
+            href: catalog.url(host, &translation.lang, &format!("/articles/{}", translation.link)),
+        })
+        .collect();
+
+    if let Some(default) = translations.iter().find(|translation| translation.lang == catalog.default_locale()) {
+        alternates.push(Alternate {
+            lang: "x-default".to_owned(),
+            href: format!("https://{}/articles/{}", host, default.link),
+        });
     }
 
-    Ok(())
+    alternates
 }
 
-fn get_peer_country(req: &HttpRequest,
-                          state: web::Data<State<'_>>) -> Option<String> {
-    let ip = req.peer_addr().unwrap().ip();
-    //let ip = "80.92.32.0".parse::<std::net::IpAddr>().unwrap();
+pub fn find_article(conn: &Connection, link: &str) -> Result<Option<Post>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("
+        SELECT *
+        FROM
+            articles
+        WHERE
+            link=?
+    ")?;
 
-    if let Some(geoip_reader) = &state.geoip_reader {
-        if let Ok(result) = geoip_reader.lookup(ip) {
-            let country_code = result.country.unwrap().iso_code.unwrap();
+    let mut rows = stmt.query(params![link])?;
 
-            Some(country_code.to_owned())
-        } else {
-            None
-        }
+    match rows.next()? {
+        Some(row) => Ok(Some(Post::from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn blocked_country(geoip_reader: &Option<Reader<'_, Country<'_>>>, ip: IpAddr) -> bool {
+    //let ip = "80.92.32.0".parse::<IpAddr>().unwrap();
+    let country_code = geoip_reader.as_ref()
+        .and_then(|geoip_reader| geoip_reader.lookup(ip).ok())
+        .and_then(|result| result.country?.iso_code);
+
+    country_code == Some("RU")
+}
+
+fn fail_russia(req: &HttpRequest,
+               state: web::Data<State<'_>>) -> Result<(), MyError> {
+    match get_peer_ip(req, &state) {
+        Some(ip) if blocked_country(&state.geoip_reader, ip) => Err(MyError::new_russia()),
+        _ => Ok(()),
+    }
+}
+
+pub fn get_peer_ip(req: &HttpRequest, state: &State<'_>) -> Option<IpAddr> {
+    if state.config.behind_proxy {
+        let addr = req.connection_info().realip_remote_addr()?.to_owned();
+
+        addr.parse::<SocketAddr>().map(|addr| addr.ip())
+            .or_else(|_| addr.parse::<IpAddr>())
+            .ok()
     } else {
-        None
+        Some(req.peer_addr()?.ip())
     }
 }
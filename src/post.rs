@@ -18,7 +18,7 @@
 use serde::{ Serialize, Serializer };
 use std::error::Error;
 use rusqlite::Row;
-use chrono::{ DateTime, NaiveDateTime, Utc };
+use chrono::{ DateTime, SecondsFormat, Utc };
 
 #[derive(Serialize)]
 pub struct Post {
@@ -30,26 +30,26 @@ pub struct Post {
     pub lastmod: Option<PostDate>,
 }
 
+#[derive(Clone)]
 pub struct PostDate(pub DateTime<Utc>);
 
 impl PostDate {
     pub fn from_timestamp(timestamp: i64) -> Option<PostDate> {
         if timestamp > 0 {
-            Some(PostDate(DateTime::<Utc>::from_utc(
-                NaiveDateTime::from_timestamp(timestamp, 0), Utc)
-            ))
+            Some(PostDate(DateTime::<Utc>::from_timestamp(timestamp, 0)?))
         } else {
             None
         }
     }
 }
 
+// Templates format dates with the `local_date` filter.
 impl Serialize for PostDate {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&self.0.format("%d.%m.%Y %H:%M").to_string())
+        serializer.serialize_str(&self.0.to_rfc3339_opts(SecondsFormat::Secs, true))
     }
 }
 
@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::thread;
+use std::error::Error;
+use std::time::Duration;
+use rusqlite::{ params, Connection };
+
+use crate::post::Post;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Published,
+    Updated,
+}
+
+pub struct Event {
+    pub change: Change,
+    pub post: Post,
+}
+
+// Something that has to happen once an article appears on the site or
+// changes there, like mailing subscribers.
+pub trait Listener: Send {
+    fn name(&self) -> &'static str;
+
+    fn notify(&self, conn: &Connection, events: &[Event]) -> Result<(), Box<dyn Error>>;
+}
+
+// Articles are written straight into the database, so publishing is noticed
+// by comparing them with the published table, which remembers what listeners
+// were already told about.
+pub struct Publisher {
+    database: String,
+    listeners: Vec<Box<dyn Listener>>,
+}
+
+impl Publisher {
+    pub fn new(database: String) -> Publisher {
+        Publisher {
+            database,
+            listeners: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, listener: Box<dyn Listener>) {
+        self.listeners.push(listener);
+    }
+
+    pub fn poll(&self, conn: &Connection) -> Result<usize, Box<dyn Error>> {
+        let mut stmt = conn.prepare("
+            SELECT
+                articles.link, published.link IS NULL
+            FROM
+                articles
+            LEFT JOIN
+                published ON published.link = articles.link
+            WHERE
+                articles.dnshow=0 AND
+                (published.link IS NULL OR published.lastmod != articles.lastmod)
+        ")?;
+
+        let changed: Vec<(String, bool)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        if changed.is_empty() {
+            return Ok(0);
+        }
+
+        let mut events = Vec::new();
+
+        for (link, new) in changed {
+            let post = conn.query_row("SELECT * FROM articles WHERE link=?", params![link], |row| {
+                Ok(Post::from_row(row))
+            })??;
+
+            events.push(Event {
+                change: if new { Change::Published } else { Change::Updated },
+                post,
+            });
+        }
+
+        // A failing listener must not make the others repeat themselves on
+        // the next poll, so the events are recorded regardless.
+        for listener in &self.listeners {
+            if let Err(e) = listener.notify(conn, &events) {
+                eprintln!("Publishing listener {} failed: {}", listener.name(), e);
+            }
+        }
+
+        for event in &events {
+            conn.execute("
+                INSERT INTO published (link, lastmod)
+                SELECT link, lastmod FROM articles WHERE link=?
+                ON CONFLICT(link) DO UPDATE SET lastmod=excluded.lastmod
+            ", params![event.post.link])?;
+        }
+
+        Ok(events.len())
+    }
+
+    // Listeners block on network, so they get a thread of their own.
+    pub fn watch(self) {
+        thread::spawn(move || {
+            let conn = match Connection::open(&self.database) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Publishing watcher database opening failed: {}", e);
+                    return;
+                }
+            };
+
+            loop {
+                if let Err(e) = self.poll(&conn) {
+                    eprintln!("Publishing watcher failed: {}", e);
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+    }
+}
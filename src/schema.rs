@@ -0,0 +1,167 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use rusqlite::Connection;
+
+// Each entry is applied once, in order, and PRAGMA user_version remembers
+// how many of them the database has already seen. Never edit an entry that
+// was released, append a new one instead.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE IF NOT EXISTS articles (
+        link TEXT NOT NULL,
+        name TEXT NOT NULL,
+        text TEXT NOT NULL,
+        short_text TEXT,
+        date INTEGER NOT NULL,
+        lastmod INTEGER NOT NULL,
+        dnshow INTEGER NOT NULL,
+        PRIMARY KEY(link)
+    );
+
+    CREATE TABLE IF NOT EXISTS hidden_articles (
+        link TEXT NOT NULL,
+        name TEXT NOT NULL,
+        text TEXT NOT NULL,
+        date INTEGER NOT NULL,
+        lastmod INTEGER NOT NULL,
+        PRIMARY KEY(link)
+    );
+", "
+    CREATE TABLE media (
+        id INTEGER PRIMARY KEY,
+        file TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        mime TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        width INTEGER,
+        height INTEGER,
+        alt TEXT NOT NULL,
+        date INTEGER NOT NULL
+    );
+", "
+    CREATE TABLE comments (
+        id INTEGER PRIMARY KEY,
+        article TEXT NOT NULL REFERENCES articles(link) ON DELETE CASCADE,
+        parent INTEGER REFERENCES comments(id) ON DELETE SET NULL,
+        author TEXT NOT NULL,
+        text TEXT NOT NULL,
+        date INTEGER NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending'
+            CHECK(status IN ('pending', 'approved', 'rejected', 'spam'))
+    );
+
+    CREATE INDEX comments_article_status ON comments(article, status);
+", "
+    CREATE TABLE messages (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        email TEXT NOT NULL,
+        text TEXT NOT NULL,
+        date INTEGER NOT NULL,
+        error TEXT
+    );
+", "
+    CREATE TABLE published (
+        link TEXT PRIMARY KEY,
+        lastmod INTEGER NOT NULL
+    );
+
+    INSERT INTO published (link, lastmod)
+    SELECT link, lastmod FROM articles WHERE dnshow=0;
+
+    CREATE TABLE subscribers (
+        id INTEGER PRIMARY KEY,
+        email TEXT NOT NULL UNIQUE COLLATE NOCASE,
+        confirmed INTEGER NOT NULL DEFAULT 0,
+        date INTEGER NOT NULL
+    );
+", "
+    CREATE TABLE webmentions (
+        id INTEGER PRIMARY KEY,
+        article TEXT NOT NULL REFERENCES articles(link) ON DELETE CASCADE,
+        source TEXT NOT NULL,
+        target TEXT NOT NULL,
+        title TEXT,
+        status TEXT NOT NULL DEFAULT 'pending'
+            CHECK(status IN ('pending', 'verified', 'invalid')),
+        date INTEGER NOT NULL,
+        UNIQUE(source, article)
+    );
+", "
+    CREATE TABLE followers (
+        id INTEGER PRIMARY KEY,
+        actor TEXT NOT NULL UNIQUE,
+        inbox TEXT NOT NULL,
+        shared_inbox TEXT,
+        date INTEGER NOT NULL
+    );
+", "
+    CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        date INTEGER NOT NULL
+    );
+
+    CREATE TABLE tokens (
+        hash TEXT PRIMARY KEY,
+        user INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        date INTEGER NOT NULL
+    );
+", "
+    CREATE TABLE tags (
+        link TEXT NOT NULL,
+        hidden INTEGER NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY(link, hidden, tag)
+    );
+
+    CREATE TABLE imported (
+        link TEXT NOT NULL,
+        hidden INTEGER NOT NULL,
+        file TEXT NOT NULL,
+        PRIMARY KEY(link, hidden)
+    );
+", "
+    CREATE TABLE translations (
+        link TEXT NOT NULL,
+        hidden INTEGER NOT NULL,
+        lang TEXT NOT NULL,
+        key TEXT NOT NULL,
+        PRIMARY KEY(link, hidden)
+    );
+
+    CREATE INDEX translations_key ON translations(key);
+", "
+    ALTER TABLE subscribers ADD COLUMN lang TEXT;
+"];
+
+pub fn migrate(conn: &mut Connection) -> rusqlite::Result<usize> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let mut applied = 0;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = conn.transaction()?;
+
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", &(index as i64 + 1))?;
+        transaction.commit()?;
+
+        applied += 1;
+    }
+
+    Ok(applied)
+}
@@ -0,0 +1,147 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+use std::task::{ Context, Poll };
+use actix_web::{ Error, HttpMessage, HttpRequest };
+use actix_web::dev::{ Service, ServiceRequest, ServiceResponse, Transform };
+use actix_web::http::{ HeaderMap, HeaderName, HeaderValue, header };
+use futures::future::{ ok, FutureExt, LocalBoxFuture, Ready };
+use openssl::{ base64, rand::rand_bytes };
+
+use crate::config::Config;
+
+pub struct CspNonce(String);
+
+pub fn nonce(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<CspNonce>().map(|nonce| nonce.0.clone())
+}
+
+pub struct SecurityHeaders {
+    config: Arc<Config>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: Arc<Config>) -> SecurityHeaders {
+        SecurityHeaders { config }
+    }
+}
+
+impl<S, B> Transform<S> for SecurityHeaders
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SecurityHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SecurityHeadersMiddleware { service, config: self.config.clone() })
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    config: Arc<Config>,
+}
+
+impl<S, B> Service for SecurityHeadersMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let config = self.config.clone();
+        let https = req.connection_info().scheme() == "https";
+        if let Some(nonce) = new_nonce() {
+            req.extensions_mut().insert(CspNonce(nonce));
+        }
+
+        let fut = self.service.call(req);
+
+        async move {
+            let mut res = fut.await?;
+            let nonce = nonce(res.request()).unwrap_or_default();
+            let headers = res.headers_mut();
+            let security = &config.security_headers;
+
+            if https && security.hsts_max_age > 0 {
+                let mut hsts = format!("max-age={}", security.hsts_max_age);
+
+                if security.hsts_include_subdomains {
+                    hsts.push_str("; includeSubDomains");
+                }
+
+                if security.hsts_preload {
+                    hsts.push_str("; preload");
+                }
+
+                insert_missing(headers, header::STRICT_TRANSPORT_SECURITY, &hsts);
+            }
+
+            insert_missing(headers, header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+            insert_missing(headers, header::REFERRER_POLICY, &security.referrer_policy);
+
+            insert_missing(headers, HeaderName::from_static("permissions-policy"),
+                           &security.permissions_policy);
+
+            insert_missing(headers, header::CONTENT_SECURITY_POLICY,
+                           &security.content_security_policy.replace("{nonce}", &nonce));
+
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
+
+fn insert_missing(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if value.is_empty() || headers.contains_key(&name) {
+        return;
+    }
+
+    match HeaderValue::from_str(value) {
+        Ok(value) => { headers.insert(name, value); }
+        Err(e) => eprintln!("Invalid {} header value: {}", name, e),
+    }
+}
+
+// Without a nonce, the policy keeps an empty 'nonce-' source, which matches
+// nothing, so inline content is blocked rather than allowed.
+fn new_nonce() -> Option<String> {
+    let mut bytes = [0; 16];
+
+    match rand_bytes(&mut bytes) {
+        Ok(()) => Some(base64::encode_block(&bytes)),
+        Err(e) => {
+            eprintln!("CSP nonce generation failed: {}", e);
+            None
+        }
+    }
+}
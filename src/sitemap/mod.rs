@@ -16,11 +16,14 @@
  */
 
 use actix_web::{ web, HttpResponse, HttpRequest };
+use rusqlite::Connection;
 use tera::Context;
 
 use crate::errors::*;
 use crate::state::State;
 use crate::sitemap::url::Url;
+use crate::i18n::Catalog;
+use crate::pages;
 use std::error::Error;
 
 mod url;
@@ -32,9 +35,19 @@ pub async fn sitemap(req: HttpRequest,
 
 async fn sitemap_inner(_: HttpRequest,
                        state: web::Data<State<'_>>) -> Result<HttpResponse, Box<dyn Error>> {
+    let context = context(&state.conn, Some(&state.catalog), &state.config.host)?;
+
+    Ok(HttpResponse::Ok().body(state.tera.render("sitemap.xml", &context)?))
+}
+
+// Without a catalog, as in the static mirror, there are no locale prefixes
+// and no alternates to point to.
+pub fn context(conn: &Connection,
+               catalog: Option<&Catalog>,
+               host: &str) -> Result<Context, Box<dyn Error>> {
     let mut context = Context::new();
 
-    let mut stmt = state.conn.prepare("
+    let mut stmt = conn.prepare("
         SELECT
             MAX(date),
             MAX(lastmod)
@@ -57,9 +70,15 @@ async fn sitemap_inner(_: HttpRequest,
         None => 0
     };
 
-    urls.push(Url::from_link("/".to_owned(), state.config.host.to_owned(), newest));
+    let mut index = Url::from_link("/".to_owned(), host.to_owned(), newest);
+
+    if let Some(catalog) = catalog {
+        index.alternates = catalog.alternates(host, "/");
+    }
+
+    urls.push(index);
 
-    let mut stmt = state.conn.prepare("
+    let mut stmt = conn.prepare("
         SELECT
             link,
             date,
@@ -71,10 +90,20 @@ async fn sitemap_inner(_: HttpRequest,
     let mut rows = stmt.query([])?;
 
     while let Some(row) = rows.next()? {
-        urls.push(Url::from_row(row, state.config.host.to_owned())?);
+        let mut url = Url::from_row(row, host.to_owned())?;
+
+        if let Some(catalog) = catalog {
+            let translations = pages::translations(conn, &row.get::<_, String>(0)?)?;
+
+            if translations.len() > 1 {
+                url.alternates = pages::translation_alternates(catalog, host, &translations);
+            }
+        }
+
+        urls.push(url);
     }
 
     context.insert("urls", &urls);
 
-    Ok(HttpResponse::Ok().body(state.tera.render("sitemap.xml", &context)?))
+    Ok(context)
 }
@@ -22,11 +22,13 @@ use serde:: { Serializer, Serialize };
 use rusqlite::{ Row };
 
 use crate::post::PostDate;
+use crate::i18n::Alternate;
 
 #[derive(Serialize)]
 pub struct Url {
     pub loc: String,
     pub lastmod: Option<SitemapDate>,
+    pub alternates: Vec<Alternate>,
 }
 
 pub struct SitemapDate(DateTime<Utc>);
@@ -63,6 +65,7 @@ impl Url {
         Url {
             loc: format!("https://{}{}", host, link),
             lastmod: SitemapDate::from_timestamp(lastmod),
+            alternates: Vec::new(),
         }
     }
 }
@@ -0,0 +1,388 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::error::Error;
+use std::sync::{ Arc, Mutex };
+use std::collections::HashMap;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use serde::{ Deserialize, Serialize };
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::state::State;
+
+// Hidden fields every protected form carries, see templates/form_guard.html.
+#[derive(Deserialize)]
+pub struct Protection {
+    #[serde(default)]
+    form_token: String,
+    #[serde(default)]
+    pow: String,
+    #[serde(default)]
+    website: String,
+}
+
+#[derive(Debug)]
+pub enum Rejection {
+    Honeypot,
+    InvalidToken,
+    Expired,
+    Reused,
+    TooFast,
+    ProofOfWork,
+    TooManyLinks,
+    BlockedWord,
+}
+
+impl Rejection {
+    // The catalog key of the explanation shown to the sender.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Rejection::Honeypot | Rejection::InvalidToken | Rejection::ProofOfWork => "form-unverified",
+            Rejection::Expired | Rejection::Reused => "form-expired",
+            Rejection::TooFast => "form-too-fast",
+            Rejection::TooManyLinks => "form-too-many-links",
+            Rejection::BlockedWord => "form-spam",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// A token that passed the checks, spent by `FormGuard::consume` once the
+// form itself is accepted, so a form sent back for its fields can be
+// corrected and sent again.
+pub struct Checked {
+    payload: String,
+    issued: i64,
+}
+
+#[derive(Serialize)]
+pub struct FormToken {
+    pub token: String,
+    pub difficulty: u32,
+}
+
+// Shared by all workers, so a token issued by one is accepted by another
+// and can only be used once.
+pub struct FormGuard {
+    config: Arc<Config>,
+    secret: [u8; 32],
+    used: Mutex<HashMap<String, i64>>,
+}
+
+impl FormGuard {
+    pub fn new(config: Arc<Config>) -> Result<FormGuard, Box<dyn Error>> {
+        let mut secret = [0; 32];
+
+        rand_bytes(&mut secret)?;
+
+        Ok(FormGuard {
+            config,
+            secret,
+            used: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn sign(&self, payload: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let key = PKey::hmac(&self.secret)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+
+        signer.update(payload.as_bytes())?;
+
+        Ok(signer.sign_to_vec()?)
+    }
+
+    pub fn issue(&self) -> Result<FormToken, Box<dyn Error>> {
+        let mut nonce = [0; 12];
+
+        rand_bytes(&mut nonce)?;
+
+        let payload = format!("{}.{}", Utc::now().timestamp(), hex(&nonce));
+        let signature = hex(&self.sign(&payload)?);
+
+        Ok(FormToken {
+            token: format!("{}.{}", payload, signature),
+            difficulty: self.config.spam.pow_difficulty,
+        })
+    }
+
+    // Checks the hidden fields first and then the submitted texts.
+    pub fn check(&self, protection: &Protection, texts: &[&str]) -> Result<Checked, Rejection> {
+        let spam = &self.config.spam;
+
+        if !protection.website.is_empty() {
+            return Err(Rejection::Honeypot);
+        }
+
+        let (payload, signature) = protection.form_token.rsplit_once('.')
+            .ok_or(Rejection::InvalidToken)?;
+
+        let expected = hex(&self.sign(payload).map_err(|_| Rejection::InvalidToken)?);
+
+        if expected.len() != signature.len() || !memcmp::eq(expected.as_bytes(), signature.as_bytes()) {
+            return Err(Rejection::InvalidToken);
+        }
+
+        let issued: i64 = payload.split('.').next()
+            .and_then(|issued| issued.parse().ok())
+            .ok_or(Rejection::InvalidToken)?;
+
+        let age = Utc::now().timestamp() - issued;
+
+        if age < spam.min_submit_time as i64 {
+            return Err(Rejection::TooFast);
+        }
+
+        if age > spam.max_token_age as i64 {
+            return Err(Rejection::Expired);
+        }
+
+        let work = sha256(format!("{}:{}", protection.form_token, protection.pow).as_bytes());
+
+        if leading_zero_bits(&work) < spam.pow_difficulty {
+            return Err(Rejection::ProofOfWork);
+        }
+
+        let links: usize = texts.iter()
+            .map(|text| text.matches("http://").count() + text.matches("https://").count())
+            .sum();
+
+        if links > spam.max_links {
+            return Err(Rejection::TooManyLinks);
+        }
+
+        let blocked = texts.iter().any(|text| {
+            let text = text.to_lowercase();
+
+            spam.blocked_words.iter().any(|word| text.contains(&word.to_lowercase()))
+        });
+
+        if blocked {
+            return Err(Rejection::BlockedWord);
+        }
+
+        let used = self.used.lock().map_err(|_| Rejection::InvalidToken)?;
+
+        if used.contains_key(payload) {
+            return Err(Rejection::Reused);
+        }
+
+        Ok(Checked { payload: payload.to_owned(), issued })
+    }
+
+    pub fn consume(&self, checked: Checked) -> Result<(), Rejection> {
+        let mut used = self.used.lock().map_err(|_| Rejection::InvalidToken)?;
+        let oldest = Utc::now().timestamp() - self.config.spam.max_token_age as i64;
+
+        used.retain(|_, issued| *issued >= oldest);
+
+        if used.insert(checked.payload, checked.issued).is_some() {
+            return Err(Rejection::Reused);
+        }
+
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+
+    for byte in hash {
+        bits += byte.leading_zeros();
+
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    bits
+}
+
+pub fn rejected(req: &HttpRequest,
+                state: &State<'_>,
+                rejection: Rejection) -> Result<HttpResponse, Box<dyn Error>> {
+    eprintln!("Form submission from {:?} rejected: {}",
+              req.connection_info().realip_remote_addr(), rejection);
+
+    let mut context = state.context(req)?;
+
+    context.insert("message", &state.catalog.translate(&state.lang(req), rejection.message(), None));
+
+    Ok(HttpResponse::BadRequest().body(state.tera.render("rejected.html", &context)?))
+}
+
+pub async fn form_token(req: HttpRequest,
+                        state: web::Data<State<'_>>) -> HttpResponse {
+    let token = try_500!(state.form_guard.issue(), state, req);
+
+    HttpResponse::Ok()
+        .header("Cache-Control", "no-store")
+        .json(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use chrono::Utc;
+    use openssl::sha::sha256;
+    use serde_json::json;
+
+    use crate::config::Config;
+    use super::{ hex, leading_zero_bits, FormGuard, Protection, Rejection };
+
+    fn guard(pow_difficulty: u32) -> FormGuard {
+        let config: Config = serde_json::from_value(json!({
+            "host": "example.org",
+            "database": "",
+            "templates": "",
+            "token": "",
+            "geoip_db_file": "",
+            "spam": {
+                "min_submit_time": 3,
+                "max_token_age": 3600,
+                "pow_difficulty": pow_difficulty,
+                "max_links": 1,
+                "blocked_words": ["Casino"],
+            },
+        })).unwrap();
+
+        FormGuard::new(Arc::new(config)).unwrap()
+    }
+
+    // A token issued the given number of seconds ago.
+    fn token(guard: &FormGuard, age: i64) -> String {
+        let payload = format!("{}.{}", Utc::now().timestamp() - age, "00112233");
+
+        format!("{}.{}", payload, hex(&guard.sign(&payload).unwrap()))
+    }
+
+    fn protection(form_token: &str) -> Protection {
+        Protection { form_token: form_token.to_owned(), pow: String::new(), website: String::new() }
+    }
+
+    #[test]
+    fn zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0x00]), 7);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn valid_token() {
+        let guard = guard(0);
+
+        assert!(guard.check(&protection(&token(&guard, 10)), &["Hello"]).is_ok());
+    }
+
+    #[test]
+    fn forged_token() {
+        let issuer = guard(0);
+        let other = guard(0);
+        let token = token(&issuer, 10);
+
+        // Every guard has a secret of its own.
+        assert!(matches!(other.check(&protection(&token), &[]), Err(Rejection::InvalidToken)));
+
+        let tampered = token.replacen("00112233", "00112234", 1);
+
+        assert!(matches!(issuer.check(&protection(&tampered), &[]), Err(Rejection::InvalidToken)));
+        assert!(matches!(issuer.check(&protection("nonsense"), &[]), Err(Rejection::InvalidToken)));
+    }
+
+    #[test]
+    fn token_age() {
+        let guard = guard(0);
+
+        assert!(matches!(guard.check(&protection(&token(&guard, 1)), &[]), Err(Rejection::TooFast)));
+        assert!(matches!(guard.check(&protection(&token(&guard, 3601)), &[]), Err(Rejection::Expired)));
+    }
+
+    #[test]
+    fn honeypot() {
+        let guard = guard(0);
+        let mut protection = protection(&token(&guard, 10));
+
+        protection.website = "http://spam.example".to_owned();
+
+        assert!(matches!(guard.check(&protection, &[]), Err(Rejection::Honeypot)));
+    }
+
+    #[test]
+    fn proof_of_work() {
+        let guard = guard(8);
+        let mut protection = protection(&token(&guard, 10));
+
+        let nonce = (0..).find(|nonce| {
+            leading_zero_bits(&sha256(format!("{}:{}", protection.form_token, nonce).as_bytes())) >= 8
+        }).unwrap();
+
+        protection.pow = (nonce + 1..).find(|nonce| {
+            leading_zero_bits(&sha256(format!("{}:{}", protection.form_token, nonce).as_bytes())) < 8
+        }).unwrap().to_string();
+
+        assert!(matches!(guard.check(&protection, &[]), Err(Rejection::ProofOfWork)));
+
+        protection.pow = nonce.to_string();
+
+        assert!(guard.check(&protection, &[]).is_ok());
+    }
+
+    #[test]
+    fn links_and_words() {
+        let guard = guard(0);
+        let token = token(&guard, 10);
+
+        assert!(guard.check(&protection(&token), &["one https://a.example", "no link"]).is_ok());
+        assert!(matches!(guard.check(&protection(&token), &["https://a.example", "http://b.example"]),
+                         Err(Rejection::TooManyLinks)));
+        assert!(matches!(guard.check(&protection(&token), &["Best CASINO in town"]),
+                         Err(Rejection::BlockedWord)));
+    }
+
+    #[test]
+    fn second_use() {
+        let guard = guard(0);
+        let token = token(&guard, 10);
+
+        // Checking alone does not spend the token, a form with errors can be
+        // sent again.
+        let first = guard.check(&protection(&token), &[]).unwrap();
+        let second = guard.check(&protection(&token), &[]).unwrap();
+
+        assert!(guard.consume(first).is_ok());
+        assert!(matches!(guard.consume(second), Err(Rejection::Reused)));
+        assert!(matches!(guard.check(&protection(&token), &[]), Err(Rejection::Reused)));
+    }
+}
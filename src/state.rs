@@ -15,16 +15,95 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::cell::Cell;
 use std::sync::{ Arc, RwLock };
+use actix_web::{ HttpMessage, HttpRequest };
+use chrono_tz::Tz;
 use geoip2::{ Country, Reader };
+use tera::Context;
 
 use crate::config::Config;
 use crate::auth::Auth;
+use crate::errors::MyError;
+use crate::security;
+use crate::page_cache::PageCache;
+use crate::assets::AssetManifest;
+use crate::templates::Templates;
+use crate::i18n::{ self, Catalog };
+use crate::spam::FormGuard;
+use crate::mail::Mailer;
+use crate::webmention::Verifier;
+use crate::activitypub::Federation;
+use crate::users;
 
 pub struct State<'a> {
-    pub tera: tera::Tera,
+    pub tera: Templates,
     pub conn: rusqlite::Connection,
     pub config: Arc<Config>,
     pub auth: RwLock<Auth>,
     pub geoip_reader: Option<Reader<'a, Country<'a>>>,
+    pub page_cache: Arc<PageCache>,
+    pub assets: Arc<AssetManifest>,
+    pub catalog: Arc<Catalog>,
+    pub data_version: Cell<i64>,
+    pub form_guard: Arc<FormGuard>,
+    pub mailer: Option<Arc<dyn Mailer>>,
+    pub webmentions: Arc<Verifier>,
+    pub activitypub: Option<Arc<Federation>>,
+}
+
+impl State<'_> {
+    pub fn context(&self, req: &HttpRequest) -> Result<Context, MyError> {
+        let mut context = Context::new();
+
+        context.insert("authorized", &self.authorized(req)?);
+        context.insert("csp_nonce", &security::nonce(req));
+
+        context.insert("lang", &self.lang(req));
+        context.insert("lang_prefix", &i18n::locale(req).map(|locale| locale.prefix()).unwrap_or_default());
+        context.insert("alternates", &self.catalog.alternates(&self.config.host, req.path()));
+        context.insert("tz", self.timezone(req).name());
+        context.insert("reader_timezone", &self.config.dates.reader_timezone);
+
+        Ok(context)
+    }
+
+    pub fn lang(&self, req: &HttpRequest) -> String {
+        match i18n::locale(req) {
+            Some(locale) => locale.lang,
+            None => self.catalog.default_locale().to_owned(),
+        }
+    }
+
+    pub fn timezone(&self, req: &HttpRequest) -> Tz {
+        let reader = match req.cookie("tz") {
+            Some(cookie) if self.config.dates.reader_timezone => cookie.value().parse().ok(),
+            _ => None,
+        };
+
+        reader.unwrap_or(self.config.dates.timezone)
+    }
+
+    pub fn authorized(&self, req: &HttpRequest) -> Result<bool, MyError> {
+        if self.auth.read()?.authorized(req) {
+            return Ok(true);
+        }
+
+        match req.cookie("auth") {
+            Some(cookie) => Ok(users::known_token(&self.conn, cookie.value())?),
+            None => Ok(false),
+        }
+    }
+
+    // SQLite bumps data_version whenever another connection commits, be it
+    // another worker or a command line tool, so cached pages go stale then.
+    pub fn check_data_version(&self) -> Result<(), rusqlite::Error> {
+        let version = self.conn.query_row("PRAGMA data_version", [], |row| row.get(0))?;
+
+        if self.data_version.replace(version) != version {
+            self.page_cache.invalidate();
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fs;
+use std::io;
+use std::thread;
+use std::error::Error;
+use std::cell::{ Cell, RefCell };
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, SystemTime };
+use tera::{ Context, Tera };
+
+use crate::config::Config;
+use crate::assets::{ AssetManifest, AssetUrl };
+use crate::images::{ ResponsiveImages, Srcset };
+use crate::page_cache::PageCache;
+use crate::i18n::{ Catalog, Translate };
+use crate::dates::{ LocalDate, RelativeDate };
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Every worker and the static export render with the same set of functions.
+pub fn load(config: &Arc<Config>,
+            assets: &Arc<AssetManifest>,
+            catalog: &Arc<Catalog>) -> tera::Result<Tera> {
+    let mut tera = Tera::new(&config.templates)?;
+
+    tera.register_function("t", Translate(catalog.clone()));
+    tera.register_filter("local_date", LocalDate(catalog.clone()));
+    tera.register_filter("relative_date", RelativeDate(catalog.clone()));
+
+    tera.register_function("asset_url", AssetUrl(assets.clone()));
+
+    tera.register_function("srcset", Srcset {
+        config: config.clone(),
+        assets: assets.clone(),
+    });
+
+    tera.register_filter("responsive_images",
+                         ResponsiveImages::new(config.clone(), assets.clone()));
+
+    Ok(tera)
+}
+
+// Tera keeps the interesting part of the message in the sources.
+pub fn error_chain(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+
+    while let Some(e) = source {
+        message.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+
+    message
+}
+
+// Templates of a worker. In development mode the watcher bumps the shared
+// generation when a template changes, and each worker reloads its own copy
+// on the next render, so a broken template only fails its own requests.
+pub struct Templates {
+    tera: RefCell<Option<Tera>>,
+    error: RefCell<Option<String>>,
+    config: Arc<Config>,
+    assets: Arc<AssetManifest>,
+    catalog: Arc<Catalog>,
+    generation: Option<Arc<AtomicU64>>,
+    loaded: Cell<u64>,
+}
+
+impl Templates {
+    pub fn new(config: Arc<Config>,
+               assets: Arc<AssetManifest>,
+               catalog: Arc<Catalog>,
+               generation: Option<Arc<AtomicU64>>) -> tera::Result<Templates> {
+        let loaded = generation.as_ref().map_or(0, |generation| generation.load(Ordering::Acquire));
+
+        let (tera, error) = match load(&config, &assets, &catalog) {
+            Ok(tera) => (Some(tera), None),
+            Err(e) if generation.is_some() => {
+                eprintln!("Template loading failed: {}", error_chain(&e));
+                (None, Some(error_chain(&e)))
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Templates {
+            tera: RefCell::new(tera),
+            error: RefCell::new(error),
+            config,
+            assets,
+            catalog,
+            generation,
+            loaded: Cell::new(loaded),
+        })
+    }
+
+    // Errors carry their whole chain in development mode, where the error
+    // page shows them, since the cause of a template error is in the source.
+    pub fn render(&self, template: &str, context: &Context) -> tera::Result<String> {
+        if self.generation.is_none() {
+            return self.render_loaded(template, context);
+        }
+
+        self.reload_if_changed();
+
+        if let Some(error) = &*self.error.borrow() {
+            return Err(tera::Error::msg(error.clone()));
+        }
+
+        self.render_loaded(template, context)
+            .map_err(|e| tera::Error::msg(error_chain(&e)))
+    }
+
+    fn render_loaded(&self, template: &str, context: &Context) -> tera::Result<String> {
+        match &*self.tera.borrow() {
+            Some(tera) => tera.render(template, context),
+            None => Err(tera::Error::msg("Templates are not loaded")),
+        }
+    }
+
+    fn reload_if_changed(&self) {
+        let generation = match &self.generation {
+            Some(generation) => generation.load(Ordering::Acquire),
+            None => return,
+        };
+
+        if self.loaded.replace(generation) == generation {
+            return;
+        }
+
+        let mut tera = self.tera.borrow_mut();
+
+        // Functions and filters survive a full reload, so the templates
+        // only have to be built anew if the first load failed.
+        let result = match tera.as_mut() {
+            Some(tera) => tera.full_reload(),
+            None => load(&self.config, &self.assets, &self.catalog).map(|loaded| *tera = Some(loaded)),
+        };
+
+        *self.error.borrow_mut() = result.err().map(|e| error_chain(&e));
+    }
+}
+
+// Polls the directory of the templates glob, as inotify is not worth a
+// dependency for a development aid, and drops the cached pages on changes.
+pub fn watch(templates: &str, page_cache: Arc<PageCache>) -> Arc<AtomicU64> {
+    let generation = Arc::new(AtomicU64::new(0));
+    let dir = glob_dir(templates);
+    let bump = generation.clone();
+
+    thread::spawn(move || {
+        let mut last = files(&dir).ok();
+
+        loop {
+            thread::sleep(POLL_INTERVAL);
+
+            let current = files(&dir).ok();
+
+            if current != last {
+                eprintln!("Templates in {} changed, reloading", dir.display());
+
+                bump.fetch_add(1, Ordering::AcqRel);
+                page_cache.invalidate();
+                last = current;
+            }
+        }
+    });
+
+    generation
+}
+
+// The part of the glob before its first wildcard, cut to a directory.
+fn glob_dir(glob: &str) -> PathBuf {
+    let prefix = match glob.find(['*', '?', '[', '{']) {
+        Some(index) => &glob[..index],
+        None => glob,
+    };
+
+    match prefix.rfind('/') {
+        Some(index) => PathBuf::from(&prefix[..=index]),
+        None => PathBuf::from("."),
+    }
+}
+
+fn files(dir: &Path) -> io::Result<Vec<(PathBuf, Option<SystemTime>, u64)>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = fs::metadata(&path)?;
+
+        if metadata.is_dir() {
+            files.extend(self::files(&path)?);
+        } else {
+            files.push((path, metadata.modified().ok(), metadata.len()));
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
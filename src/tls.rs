@@ -0,0 +1,184 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::error::Error;
+use std::collections::HashMap;
+use std::sync::{ Arc, RwLock };
+use std::time::{ Duration, SystemTime };
+use actix_rt::signal::unix::{ signal, SignalKind };
+use actix_rt::time::interval;
+use openssl::ssl::{
+    NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod
+};
+
+use crate::errors::MyError;
+use crate::config::Config;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Certificates {
+    config: Arc<Config>,
+    contexts: RwLock<Contexts>,
+}
+
+struct Contexts {
+    default: SslContext,
+    by_host: HashMap<String, SslContext>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl Certificates {
+    pub fn load(config: Arc<Config>) -> Result<Certificates, Box<dyn Error>> {
+        let contexts = RwLock::new(Contexts::load(&config)?);
+
+        Ok(Certificates { config, contexts })
+    }
+
+    pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+        let contexts = Contexts::load(&self.config)?;
+
+        *self.contexts.write().map_err(MyError::from)? = contexts;
+
+        Ok(())
+    }
+
+    // Every handshake goes through the servername callback, even without
+    // SNI, so the context chosen here is always the most recently loaded one.
+    pub fn acceptor(self: &Arc<Self>) -> Result<SslAcceptorBuilder, Box<dyn Error>> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        let certificates = self.clone();
+
+        builder.set_servername_callback(move |ssl, _| {
+            let name = ssl.servername(NameType::HOST_NAME).map(str::to_lowercase);
+            let contexts = certificates.contexts.read().map_err(|_| SniError::ALERT_FATAL)?;
+
+            let context = name.and_then(|name| contexts.find(&name))
+                .unwrap_or(&contexts.default);
+
+            ssl.set_ssl_context(context).map_err(|_| SniError::ALERT_FATAL)
+        });
+
+        Ok(builder)
+    }
+
+    fn changed(&self) -> bool {
+        match self.contexts.read() {
+            Ok(contexts) => contexts.modified != modification_times(&self.config),
+            Err(_) => false,
+        }
+    }
+
+    fn reload_or_log(&self) {
+        match self.reload() {
+            Ok(()) => eprintln!("TLS certificates reloaded"),
+            Err(e) => eprintln!("TLS certificates reloading failed: {}", e),
+        }
+    }
+}
+
+impl Contexts {
+    fn load(config: &Config) -> Result<Contexts, Box<dyn Error>> {
+        let modified = modification_times(config);
+
+        let mut by_host = HashMap::new();
+        let mut first = None;
+
+        for certificate in &config.certificates {
+            let context = new_acceptor(&certificate.priv_key_file, &certificate.cert_chain_file)?
+                .build()
+                .into_context();
+
+            for host in &certificate.hosts {
+                by_host.insert(host.to_lowercase(), context.clone());
+            }
+
+            first.get_or_insert(context);
+        }
+
+        let default = match (&config.priv_key_file, &config.cert_chain_file) {
+            (Some(priv_key_file), Some(cert_chain_file)) => {
+                new_acceptor(priv_key_file, cert_chain_file)?.build().into_context()
+            }
+
+            _ => first.ok_or("No TLS certificate is configured")?,
+        };
+
+        Ok(Contexts { default, by_host, modified })
+    }
+
+    fn find(&self, name: &str) -> Option<&SslContext> {
+        if let Some(context) = self.by_host.get(name) {
+            return Some(context);
+        }
+
+        let (_, parent) = name.split_once('.')?;
+
+        self.by_host.get(&format!("*.{}", parent))
+    }
+}
+
+pub fn watch(certificates: Arc<Certificates>) {
+    let on_hangup = certificates.clone();
+
+    actix_rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                eprintln!("SIGHUP handler installing failed: {}", e);
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            on_hangup.reload_or_log();
+        }
+    });
+
+    actix_rt::spawn(async move {
+        let mut interval = interval(POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if certificates.changed() {
+                certificates.reload_or_log();
+            }
+        }
+    });
+}
+
+fn new_acceptor(priv_key_file: &str,
+                cert_chain_file: &str) -> Result<SslAcceptorBuilder, Box<dyn Error>> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+
+    builder.set_private_key_file(priv_key_file, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(cert_chain_file)?;
+    builder.check_private_key()?;
+
+    Ok(builder)
+}
+
+fn modification_times(config: &Config) -> Vec<Option<SystemTime>> {
+    let files = config.certificates.iter()
+        .flat_map(|certificate| [&certificate.priv_key_file, &certificate.cert_chain_file])
+        .chain(config.priv_key_file.iter())
+        .chain(config.cert_chain_file.iter());
+
+    files.map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
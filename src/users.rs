@@ -0,0 +1,98 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use rusqlite::{ params, Connection, OptionalExtension };
+
+// Besides the token from the config, everyone added with `website user add`
+// signs in with tokens of their own. Only hashes of those are stored, so a
+// token is shown once, when it is created.
+fn hash(token: &str) -> String {
+    sha256(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn generate_token() -> Result<String, Box<dyn Error>> {
+    let mut bytes = [0; 32];
+
+    rand_bytes(&mut bytes)?;
+
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub fn add(conn: &Connection, name: &str) -> Result<String, Box<dyn Error>> {
+    let exists: i64 = conn.query_row("SELECT COUNT(*) FROM users WHERE name=?", params![name], |row| row.get(0))?;
+
+    if exists > 0 {
+        return Err(format!("User {} already exists", name).into());
+    }
+
+    conn.execute("
+        INSERT INTO users (name, date)
+        VALUES (?, strftime('%s', 'now'))
+    ", params![name])?;
+
+    create_token(conn, name)
+}
+
+// Foreign keys are not enforced, so the tokens of the user are deleted here
+// rather than by the cascade in the schema.
+pub fn remove(conn: &Connection, name: &str) -> Result<(), Box<dyn Error>> {
+    let user = id(conn, name)?;
+
+    conn.execute("DELETE FROM tokens WHERE user=?", params![user])?;
+    conn.execute("DELETE FROM users WHERE id=?", params![user])?;
+
+    Ok(())
+}
+
+pub fn create_token(conn: &Connection, name: &str) -> Result<String, Box<dyn Error>> {
+    let user = id(conn, name)?;
+    let token = generate_token()?;
+
+    conn.execute("
+        INSERT INTO tokens (hash, user, date)
+        VALUES (?, ?, strftime('%s', 'now'))
+    ", params![hash(&token), user])?;
+
+    Ok(token)
+}
+
+fn id(conn: &Connection, name: &str) -> Result<i64, Box<dyn Error>> {
+    let user: Option<i64> = conn.query_row("
+        SELECT
+            id
+        FROM
+            users
+        WHERE
+            name=?
+    ", params![name], |row| row.get(0)).optional()?;
+
+    Ok(user.ok_or_else(|| format!("There is no user {}", name))?)
+}
+
+pub fn known_token(conn: &Connection, token: &str) -> rusqlite::Result<bool> {
+    conn.query_row("
+        SELECT
+            COUNT(*)
+        FROM
+            tokens
+        WHERE
+            hash=?
+    ", params![hash(token)], |row| row.get(0)).map(|count: i64| count > 0)
+}
@@ -0,0 +1,482 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::thread;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ self, Receiver };
+use std::collections::{ HashMap, HashSet };
+use actix_web::{ web, HttpRequest, HttpResponse };
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use rusqlite::{ params, Connection, OptionalExtension };
+use serde::{ Deserialize, Serialize };
+use url::{ Url, form_urlencoded };
+
+use crate::assets::encode_path;
+use crate::config::Config;
+use crate::errors::*;
+use crate::http::{ HttpClient, Request };
+use crate::pages::get_peer_ip;
+use crate::post::PostDate;
+use crate::publish::{ Event, Listener };
+use crate::state::State;
+
+const MAX_TITLE_LENGTH: usize = 200;
+
+// Every mention costs a fetch of somebody else's page, so an address gets
+// only so many of them an hour.
+const MAX_PER_HOUR: u32 = 20;
+
+static TITLE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+static LINK: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?i)href\s*=\s*"(https?://[^"]+)""#).unwrap());
+static LINK_HEADER: Lazy<Regex> = Lazy::new(|| Regex::new(r#"<([^>]*)>\s*;[^,]*rel\s*=\s*"?([^",]*)"?"#).unwrap());
+static LINK_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<(?:link|a)\b[^>]*>").unwrap());
+static REL: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?i)\brel\s*=\s*["']([^"']*)["']"#).unwrap());
+static HREF: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?i)\bhref\s*=\s*["']([^"']*)["']"#).unwrap());
+
+#[derive(Serialize)]
+pub struct Webmention {
+    pub source: String,
+    pub title: Option<String>,
+    pub date: Option<PostDate>,
+}
+
+#[derive(Deserialize)]
+pub struct WebmentionFormData {
+    source: String,
+    target: String,
+}
+
+pub fn for_article(conn: &Connection, article: &str) -> Result<Vec<Webmention>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("
+        SELECT
+            source, title, date
+        FROM
+            webmentions
+        WHERE
+            article=? AND status='verified'
+        ORDER BY
+            date
+    ")?;
+
+    let mentions = stmt
+        .query_map(params![article], |row| Ok(Webmention {
+            source: row.get(0)?,
+            title: row.get(1)?,
+            date: PostDate::from_timestamp(row.get(2)?),
+        }))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(mentions)
+}
+
+fn article_url(config: &Config, link: &str) -> String {
+    format!("https://{}/articles/{}", config.host, encode_path(link))
+}
+
+// Mentions are checked one by one in the background, because that means
+// fetching somebody else's page.
+pub struct Verifier {
+    queue: mpsc::Sender<i64>,
+    senders: Mutex<HashMap<IpAddr, (i64, u32)>>,
+}
+
+impl Verifier {
+    pub fn start(database: String, client: Arc<dyn HttpClient>) -> Verifier {
+        let (queue, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            if let Err(e) = verify_loop(&database, client.as_ref(), receiver) {
+                eprintln!("Webmention verifier stopped: {}", e);
+            }
+        });
+
+        Verifier { queue, senders: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        let mut senders = match self.senders.lock() {
+            Ok(senders) => senders,
+            Err(_) => return false,
+        };
+
+        let now = Utc::now().timestamp();
+
+        senders.retain(|_, (since, _)| now - *since < 60 * 60);
+
+        let (_, count) = senders.entry(ip).or_insert((now, 0));
+        *count += 1;
+
+        *count <= MAX_PER_HOUR
+    }
+
+    pub fn enqueue(&self, id: i64) {
+        if self.queue.send(id).is_err() {
+            eprintln!("Webmention {} queueing failed, the verifier is gone", id);
+        }
+    }
+}
+
+fn verify_loop(database: &str,
+               client: &dyn HttpClient,
+               receiver: Receiver<i64>) -> Result<(), Box<dyn Error>> {
+    let conn = Connection::open(database)?;
+
+    // Whatever was left unverified by the previous run goes first.
+    let mut stmt = conn.prepare("SELECT id FROM webmentions WHERE status='pending'")?;
+    let pending: Vec<i64> = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+
+    for id in pending.into_iter().chain(receiver) {
+        if let Err(e) = verify(&conn, client, id) {
+            eprintln!("Webmention {} verification failed: {}", id, e);
+        }
+    }
+
+    Ok(())
+}
+
+// Other connections are noticed through data_version, so cached pages show
+// the new mention without an explicit invalidation.
+fn verify(conn: &Connection, client: &dyn HttpClient, id: i64) -> Result<(), Box<dyn Error>> {
+    let mention: Option<(String, String)> = conn.query_row("
+        SELECT
+            source, target
+        FROM
+            webmentions
+        WHERE
+            id=? AND status='pending'
+    ", params![id], |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+
+    let (source, target) = match mention {
+        Some(mention) => mention,
+        None => return Ok(()),
+    };
+
+    let response = client.send(Request::get(&source).header("Accept", "text/html"))?;
+    let html = response.text();
+
+    let valid = response.success()
+        && (html.contains(&target) || html.contains(&target.replace('&', "&amp;")));
+
+    if valid {
+        conn.execute("
+            UPDATE webmentions SET status='verified', title=? WHERE id=?
+        ", params![title(&html), id])?;
+    } else {
+        conn.execute("UPDATE webmentions SET status='invalid' WHERE id=?", params![id])?;
+    }
+
+    Ok(())
+}
+
+fn title(html: &str) -> Option<String> {
+    let title = TITLE.captures(html)?[1].trim().to_owned();
+
+    let title = title
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    if title.is_empty() {
+        None
+    } else {
+        Some(title.chars().take(MAX_TITLE_LENGTH).collect())
+    }
+}
+
+pub async fn webmention(req: HttpRequest,
+                        state: web::Data<State<'_>>,
+                        form: web::Form<WebmentionFormData>) -> HttpResponse {
+    try_500!(webmention_inner(req, state, form).await, state, req)
+}
+
+async fn webmention_inner(req: HttpRequest,
+                          state: web::Data<State<'_>>,
+                          form: web::Form<WebmentionFormData>) -> Result<HttpResponse, Box<dyn Error>> {
+    if let Some(ip) = get_peer_ip(&req, &state) {
+        if !state.webmentions.allow(ip) {
+            return Ok(HttpResponse::TooManyRequests().body("Too many webmentions, try again later"));
+        }
+    }
+
+    let bad_request = |reason: &str| Ok(HttpResponse::BadRequest().body(reason.to_owned()));
+
+    let (source, target) = match (Url::parse(&form.source), Url::parse(&form.target)) {
+        (Ok(source), Ok(target)) => (source, target),
+        _ => return bad_request("Source and target must be URLs"),
+    };
+
+    let http = |url: &Url| url.scheme() == "http" || url.scheme() == "https";
+
+    if !http(&source) || !http(&target) {
+        return bad_request("Only http and https URLs are supported");
+    }
+
+    if source.as_str() == target.as_str() {
+        return bad_request("Source and target must differ");
+    }
+
+    let own_host = target.host_str()
+        .map(|host| state.config.hosts().any(|known| known.eq_ignore_ascii_case(host)))
+        .unwrap_or(false);
+
+    let link = target.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect::<Vec<_>>())
+        .and_then(|segments| match segments.as_slice() {
+            ["articles", link] => percent_decode_str(link).decode_utf8().ok().map(|link| link.into_owned()),
+            _ => None,
+        });
+
+    let link = match link {
+        Some(link) if own_host => link,
+        _ => return bad_request("Target is not an article on this site"),
+    };
+
+    let article: Option<String> = state.conn.query_row("
+        SELECT
+            link
+        FROM
+            articles
+        WHERE
+            link=? AND dnshow=0
+    ", params![link], |row| row.get(0)).optional()?;
+
+    let article = match article {
+        Some(article) => article,
+        None => return bad_request("Target is not an article on this site"),
+    };
+
+    // A repeated mention is verified again, the source may have changed.
+    state.conn.execute("
+        INSERT INTO webmentions (article, source, target, status, date)
+        VALUES (?, ?, ?, 'pending', strftime('%s', 'now'))
+        ON CONFLICT(source, article) DO UPDATE SET
+            target=excluded.target,
+            status='pending'
+    ", params![article, form.source, form.target])?;
+
+    let id: i64 = state.conn.query_row("
+        SELECT
+            id
+        FROM
+            webmentions
+        WHERE
+            source=? AND article=?
+    ", params![form.source, article], |row| row.get(0))?;
+
+    state.webmentions.enqueue(id);
+
+    Ok(HttpResponse::Accepted().body("Webmention is queued for verification"))
+}
+
+// Tells every page an article links to that it was mentioned.
+pub struct Sender {
+    pub config: Arc<Config>,
+    pub client: Arc<dyn HttpClient>,
+}
+
+impl Sender {
+    fn links(&self, html: &str) -> Vec<String> {
+        let mut seen = HashSet::new();
+
+        LINK.captures_iter(html)
+            .map(|caps| caps[1].replace("&amp;", "&"))
+            .filter(|link| {
+                let own = Url::parse(link).ok()
+                    .and_then(|url| url.host_str().map(str::to_owned))
+                    .map(|host| self.config.hosts().any(|known| known.eq_ignore_ascii_case(&host)))
+                    .unwrap_or(true);
+
+                !own && seen.insert(link.clone())
+            })
+            .collect()
+    }
+
+    fn endpoint(&self, target: &str) -> Result<Option<Url>, Box<dyn Error>> {
+        let response = self.client.send(Request::get(target).header("Accept", "text/html"))?;
+
+        if !response.success() {
+            return Ok(None);
+        }
+
+        let base = Url::parse(&response.url)?;
+
+        for value in response.headers("Link") {
+            for caps in LINK_HEADER.captures_iter(value) {
+                if caps[2].split_whitespace().any(|rel| rel == "webmention") {
+                    return Ok(Some(base.join(&caps[1])?));
+                }
+            }
+        }
+
+        let html = response.text();
+
+        for tag in LINK_TAG.find_iter(&html) {
+            let webmention = REL.captures(tag.as_str())
+                .map(|caps| caps[1].split_whitespace().any(|rel| rel == "webmention"))
+                .unwrap_or(false);
+
+            if let (true, Some(caps)) = (webmention, HREF.captures(tag.as_str())) {
+                return Ok(Some(base.join(&caps[1].replace("&amp;", "&"))?));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn send(&self, source: &str, target: &str) -> Result<(), Box<dyn Error>> {
+        let endpoint = match self.endpoint(target)? {
+            Some(endpoint) => endpoint,
+            None => return Ok(()),
+        };
+
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("source", source)
+            .append_pair("target", target)
+            .finish();
+
+        let response = self.client.send(Request::post(endpoint.as_str(),
+                                                      "application/x-www-form-urlencoded",
+                                                      body.into_bytes()))?;
+
+        if !response.success() {
+            return Err(format!("{} answered {}", endpoint, response.status).into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Listener for Sender {
+    fn name(&self) -> &'static str {
+        "webmention"
+    }
+
+    fn notify(&self, _: &Connection, events: &[Event]) -> Result<(), Box<dyn Error>> {
+        for event in events {
+            let source = article_url(&self.config, &event.post.link);
+
+            for target in self.links(&event.post.text) {
+                if let Err(e) = self.send(&source, &target) {
+                    eprintln!("Webmention from {} to {} failed: {}", source, target, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::http::fake::FakeClient;
+    use super::Sender;
+
+    fn sender(client: FakeClient) -> (Sender, Arc<FakeClient>) {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "host": "example.org",
+            "database": "",
+            "templates": "",
+            "token": "",
+            "geoip_db_file": "",
+        })).unwrap();
+
+        let client = Arc::new(client);
+
+        (Sender { config: Arc::new(config), client: client.clone() }, client)
+    }
+
+    #[test]
+    fn links_skip_own_and_repeated() {
+        let (sender, _) = sender(FakeClient::default());
+
+        let html = r#"
+            <a href="https://other.net/post?a=1&amp;b=2">one</a>
+            <a href="https://example.org/articles/self">self</a>
+            <a HREF = "http://third.net/">two</a>
+            <a href="https://other.net/post?a=1&amp;b=2">again</a>
+            <a href="/relative">relative</a>
+            <a href="mailto:me@other.net">mail</a>
+        "#;
+
+        assert_eq!(sender.links(html), vec!["https://other.net/post?a=1&b=2", "http://third.net/"]);
+    }
+
+    #[test]
+    fn endpoint_from_link_header() {
+        let client = FakeClient::default().page(
+            "https://other.net/post",
+            "https://other.net/posts/1",
+            &[("Link", r#"<https://other.net/style.css>; rel="stylesheet", <../mentions>; rel="webmention""#)],
+            r#"<link rel="webmention" href="/ignored">"#,
+        );
+
+        let (sender, _) = sender(client);
+
+        assert_eq!(sender.endpoint("https://other.net/post").unwrap().unwrap().as_str(),
+                   "https://other.net/mentions");
+    }
+
+    #[test]
+    fn endpoint_from_html() {
+        let client = FakeClient::default()
+            .page("https://other.net/a", "https://other.net/a", &[],
+                  r#"<head><LINK href="/webmention?x=1&amp;y=2" rel="webmention"></head>"#)
+            .page("https://other.net/b", "https://other.net/b", &[],
+                  r#"<a rel='nofollow webmention' href='https://mentions.net/endpoint'>mention</a>"#)
+            .page("https://other.net/c", "https://other.net/c", &[],
+                  r#"<a rel="webmentions" href="/no">no</a>"#);
+
+        let (sender, _) = sender(client);
+
+        assert_eq!(sender.endpoint("https://other.net/a").unwrap().unwrap().as_str(),
+                   "https://other.net/webmention?x=1&y=2");
+        assert_eq!(sender.endpoint("https://other.net/b").unwrap().unwrap().as_str(),
+                   "https://mentions.net/endpoint");
+        assert_eq!(sender.endpoint("https://other.net/c").unwrap(), None);
+        assert_eq!(sender.endpoint("https://other.net/missing").unwrap(), None);
+    }
+
+    #[test]
+    fn send_posts_to_endpoint() {
+        let client = FakeClient::default()
+            .page("https://other.net/post", "https://other.net/post",
+                  &[("Link", "<https://other.net/mentions>; rel=webmention")], "")
+            .page("https://other.net/mentions", "https://other.net/mentions", &[], "");
+
+        let (sender, client) = sender(client);
+
+        sender.send("https://example.org/articles/a", "https://other.net/post").unwrap();
+
+        let requests = client.requests.lock().unwrap();
+        let post = &requests[1];
+
+        assert_eq!(post.method, "POST");
+        assert_eq!(post.url, "https://other.net/mentions");
+        assert_eq!(post.body.as_deref(),
+                   Some(&b"source=https%3A%2F%2Fexample.org%2Farticles%2Fa&target=https%3A%2F%2Fother.net%2Fpost"[..]));
+    }
+}
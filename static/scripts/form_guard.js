@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) 2022 Мира Странная <rsxrwscjpzdzwpxaujrr@yahoo.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU Affero General Public License
+ * as published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+"use strict";
+
+// Pages with forms are cached and shared, so each form fetches its own
+// single-use token once the visitor starts filling it in, and solves the
+// proof of work for it in the background.
+
+function leadingZeroBits(bytes) {
+    let bits = 0;
+
+    for (const byte of bytes) {
+        if (byte === 0) {
+            bits += 8;
+        } else {
+            bits += Math.clz32(byte) - 24;
+            break;
+        }
+    }
+
+    return bits;
+}
+
+const K = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+function rotate(x, n) {
+    return (x >>> n) | (x << (32 - n));
+}
+
+// crypto.subtle only exists in secure contexts, so plain HTTP listeners get
+// the same hash computed here.
+function sha256(data) {
+    const padded = new Uint8Array(Math.ceil((data.length + 9) / 64) * 64);
+    const view = new DataView(padded.buffer);
+
+    padded.set(data);
+    padded[data.length] = 0x80;
+    view.setUint32(padded.length - 8, Math.floor(data.length / 0x20000000));
+    view.setUint32(padded.length - 4, (data.length * 8) >>> 0);
+
+    const state = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+        0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    const w = new Uint32Array(64);
+
+    for (let offset = 0; offset < padded.length; offset += 64) {
+        for (let i = 0; i < 16; i++) {
+            w[i] = view.getUint32(offset + i * 4);
+        }
+
+        for (let i = 16; i < 64; i++) {
+            const s0 = rotate(w[i - 15], 7) ^ rotate(w[i - 15], 18) ^ (w[i - 15] >>> 3);
+            const s1 = rotate(w[i - 2], 17) ^ rotate(w[i - 2], 19) ^ (w[i - 2] >>> 10);
+
+            w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+        }
+
+        let [a, b, c, d, e, f, g, h] = state;
+
+        for (let i = 0; i < 64; i++) {
+            const s1 = rotate(e, 6) ^ rotate(e, 11) ^ rotate(e, 25);
+            const t1 = (h + s1 + ((e & f) ^ (~e & g)) + K[i] + w[i]) | 0;
+            const s0 = rotate(a, 2) ^ rotate(a, 13) ^ rotate(a, 22);
+            const t2 = (s0 + ((a & b) ^ (a & c) ^ (b & c))) | 0;
+
+            h = g;
+            g = f;
+            f = e;
+            e = (d + t1) | 0;
+            d = c;
+            c = b;
+            b = a;
+            a = (t1 + t2) | 0;
+        }
+
+        [a, b, c, d, e, f, g, h].forEach((value, i) => {
+            state[i] = (state[i] + value) | 0;
+        });
+    }
+
+    const hash = new Uint8Array(32);
+    const hashView = new DataView(hash.buffer);
+
+    state.forEach((value, i) => hashView.setUint32(i * 4, value >>> 0));
+
+    return hash;
+}
+
+async function digest(data) {
+    if (window.crypto && crypto.subtle) {
+        return new Uint8Array(await crypto.subtle.digest("SHA-256", data));
+    }
+
+    return sha256(data);
+}
+
+async function solve(token, difficulty) {
+    const encoder = new TextEncoder();
+
+    for (let counter = 0; ; counter++) {
+        const data = encoder.encode(token + ":" + counter);
+        const hash = await digest(data);
+
+        if (leadingZeroBits(hash) >= difficulty) {
+            return counter.toString();
+        }
+    }
+}
+
+async function prepare(form) {
+    const response = await fetch("/forms/token", { cache: "no-store" });
+    const { token, difficulty } = await response.json();
+
+    form.elements.form_token.value = token;
+    form.elements.pow.value = await solve(token, difficulty);
+}
+
+function guard(form) {
+    let ready = null;
+
+    const start = () => {
+        if (ready === null) {
+            ready = prepare(form);
+        }
+
+        return ready;
+    };
+
+    form.addEventListener("focusin", start);
+
+    form.addEventListener("submit", (event) => {
+        event.preventDefault();
+
+        for (const button of form.querySelectorAll("[type=submit]")) {
+            button.disabled = true;
+        }
+
+        start().then(() => form.submit(), () => form.submit());
+    });
+}
+
+for (const form of document.querySelectorAll("form[data-guarded]")) {
+    guard(form);
+}